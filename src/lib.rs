@@ -3,6 +3,7 @@ pub mod signal;
 pub mod rag;
 
 use std::convert::TryInto;
+use std::fmt::Write as _;
 
 use anyhow::{bail, Context as _};
 use directories::ProjectDirs;
@@ -23,18 +24,32 @@ use presage::{
 };
 use presage_store_sled::MigrationConflictStrategy;
 use presage_store_sled::SledStore;
-use sqlx::{Pool, Postgres};
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tracing::{debug, error};
 use types::Args;
 use types::Cmd;
 use types::Recipient;
-use signal::format_message::format_message;
+use signal::format_message::{format_message, MessageEverything};
+use signal::bot::BotConfig;
+use signal::import_desktop::{import_desktop, ImportStats};
+use signal::index_history::{index_history, HistoryStats};
 use signal::receive::receive;
 use signal::send::send;
 use signal::upload_attachments::upload_attachments;
+use rag::ask::{ask_batch, AskOptions};
+use rag::embedder::EmbedderConfig;
+use rag::encryption::{encrypt_existing, EncryptStats};
+use rag::export::{export_messages, import_messages, ExportOptions, ImportedRows};
+use rag::index::{create_index, describe_index, rebuild_index};
+use rag::migrations::migration_status;
+use rag::questions::get_questions;
+use rag::reembed::{reembed, ReembedStats};
+use rag::search::{search_messages, SearchFilter};
+use rag::threads::reply_chain;
+use rag::vector_db::VectorDb;
+use rag::windows::build_windows;
 
-pub async fn entry_point(args: Args, pg_pool: &Pool<Postgres>) -> anyhow::Result<String> {
+pub async fn entry_point(args: Args, vector_db: &VectorDb) -> anyhow::Result<String> {
     env_logger::Builder::from_env(
         Env::default().default_filter_or(format!("{}=warn", env!("CARGO_PKG_NAME"))),
     )
@@ -54,10 +69,23 @@ pub async fn entry_point(args: Args, pg_pool: &Pool<Postgres>) -> anyhow::Result
         OnNewIdentity::Trust,
     )
     .await?;
-    run(args.subcommand, config_store, pg_pool).await
+    run(
+        args.subcommand,
+        config_store,
+        vector_db,
+        &args.embedder,
+        &args.ollama_url,
+    )
+    .await
 }
 
-async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres>) -> anyhow::Result<String> {
+async fn run<S: Store>(
+    subcommand: Cmd,
+    config_store: S,
+    vector_db: &VectorDb,
+    embedder_config: &EmbedderConfig,
+    ollama_url: &str,
+) -> anyhow::Result<String> {
     let mut response = String::new();
 
     match subcommand {
@@ -81,7 +109,7 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
             .await?;
 
             // ask for confirmation code here
-            response = format!("input confirmation code (followed by RETURN): ");
+            println!("input confirmation code (followed by RETURN): ");
             let stdin = io::stdin();
             let reader = BufReader::new(stdin);
             if let Some(confirmation_code) = reader.lines().next_line().await? {
@@ -153,15 +181,20 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                     ""
                 };
 
-                response = format!(
+                writeln!(
+                    response,
                     "- Device {} {}\n  Name: {}\n  Created: {}\n  Last seen: {}",
                     device.id, current_marker, device_name, device.created, device.last_seen,
-                );
+                )?;
             }
         }
-        Cmd::Receive => {
+        Cmd::Receive { bot } => {
             let mut manager = Manager::load_registered(config_store).await?;
-            receive(&mut manager, pg_pool).await?;
+            let bot = BotConfig {
+                ollama_url: ollama_url.to_string(),
+                ..bot
+            };
+            receive(&mut manager, vector_db, &bot).await?;
             response = "contact Exiting".to_string();
         }
         Cmd::Send {
@@ -177,7 +210,7 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 ..Default::default()
             };
 
-            send(&mut manager, Recipient::Contact(uuid), data_message, pg_pool).await?;
+            send(&mut manager, Recipient::Contact(uuid), data_message, vector_db).await?;
         }
         Cmd::SendToGroup {
             message,
@@ -197,7 +230,13 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 ..Default::default()
             };
 
-            send(&mut manager, Recipient::Group(master_key), data_message, pg_pool).await?;
+            send(
+                &mut manager,
+                Recipient::Group(master_key),
+                data_message,
+                vector_db,
+            )
+            .await?;
         }
         Cmd::RetrieveProfile {
             uuid,
@@ -242,10 +281,11 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                         },
                     )) => {
                         let key = hex::encode(group_master_key);
-                        response = format!(
+                        writeln!(
+                            response,
                             "{key} {title}: {description:?} / revision {revision} / {} members",
                             members.len()
-                        );
+                        )?;
                     }
                     Err(error) => {
                         error!(%error, "failed to deserialize group");
@@ -262,7 +302,7 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 ..
             } in manager.store().contacts().await?.flatten()
             {
-                response = format!("{uuid} / {phone_number:?} / {name}");
+                writeln!(response, "{uuid} / {phone_number:?} / {name}")?;
             }
         }
         Cmd::ListStickerPacks => {
@@ -270,18 +310,20 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
             for sticker_pack in manager.store().sticker_packs().await? {
                 match sticker_pack {
                     Ok(sticker_pack) => {
-                        response = format!(
+                        writeln!(
+                            response,
                             "title={} author={}",
                             sticker_pack.manifest.title, sticker_pack.manifest.author,
-                        );
+                        )?;
                         for sticker in sticker_pack.manifest.stickers {
-                            response = format!(
+                            writeln!(
+                                response,
                                 "\tid={} emoji={} content_type={} bytes={}",
                                 sticker.id,
                                 sticker.emoji.unwrap_or_default(),
                                 sticker.content_type.unwrap_or_default(),
                                 sticker.bytes.unwrap_or_default().len(),
-                            )
+                            )?;
                         }
                     }
                     Err(error) => {
@@ -313,10 +355,14 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 .await?
                 .filter_map(Result::ok)
                 .filter(|c| uuid.map_or_else(|| true, |u| c.uuid == u))
-                .filter(|c| c.phone_number == phone_number)
+                .filter(|c| {
+                    phone_number
+                        .as_ref()
+                        .map_or(true, |p| c.phone_number.as_ref() == Some(p))
+                })
                 .filter(|c| name.as_ref().map_or(true, |n| c.name.contains(n)))
             {
-                response = format!("{contact:#?}");
+                writeln!(response, "{contact:#?}")?;
             }
         }
         Cmd::SyncContacts => {
@@ -329,7 +375,7 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 .context("failed to initialize messages stream")?;
             pin_mut!(messages);
 
-            response = format!("synchronizing messages until we get contacts (dots are messages synced from the past timeline)");
+            println!("synchronizing messages until we get contacts (dots are messages synced from the past timeline)");
 
             while let Some(content) = messages.next().await {
                 match content {
@@ -358,7 +404,20 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
                 .await?
                 .filter_map(Result::ok)
             {
-                format_message(&manager,  &msg).await;
+                let MessageEverything {
+                    direction,
+                    contact,
+                    group,
+                    body,
+                    ..
+                } = format_message(&manager, &msg).await;
+                writeln!(
+                    response,
+                    "{} {}: {}",
+                    direction.map(|d| d.to_string()).unwrap_or_default(),
+                    group.or(contact).unwrap_or_default(),
+                    body.unwrap_or_default(),
+                )?;
             }
         }
         Cmd::Stats => {
@@ -409,6 +468,194 @@ async fn run<S: Store>(subcommand: Cmd, config_store: S, pg_pool: &Pool<Postgres
 
             response = format!("{stats:#?}")
         }
+        Cmd::Search {
+            query,
+            limit,
+            contact,
+            group,
+            direction,
+            since,
+            until,
+            thread,
+            per_message,
+            include_revisions,
+            reaction,
+            reacted_by_me,
+            reaction_weight,
+            granularity,
+            tuning,
+        } => {
+            let filter = SearchFilter {
+                limit,
+                contact,
+                group,
+                direction,
+                since,
+                until,
+                thread,
+                per_message,
+                include_revisions,
+                reaction,
+                reacted_by_me,
+                reaction_weight,
+                granularity,
+                tuning,
+            };
+            for result in search_messages(vector_db, &query, &filter).await? {
+                writeln!(response, "{}", result.to_line())?;
+            }
+        }
+        Cmd::Index {
+            kind,
+            rebuild,
+            params,
+        } => {
+            match kind {
+                Some(kind) if !rebuild => create_index(&vector_db.pool, kind, &params).await?,
+                _ => rebuild_index(&vector_db.pool).await?,
+            }
+            if let Some(definition) = describe_index(&vector_db.pool).await? {
+                writeln!(response, "{definition}")?;
+            }
+        }
+        Cmd::Migrate => {
+            // Pending migrations were applied when connecting, this reports the result
+            for status in migration_status(&vector_db.pool).await? {
+                match status.applied_at {
+                    Some(applied_at) => writeln!(
+                        response,
+                        "{:>4} {} (applied {})",
+                        status.version, status.description, applied_at
+                    )?,
+                    None => writeln!(
+                        response,
+                        "{:>4} {} (pending)",
+                        status.version, status.description
+                    )?,
+                }
+            }
+        }
+        Cmd::BuildWindows { thread, config } => {
+            let stats = build_windows(vector_db, &config, thread.as_deref()).await?;
+            writeln!(
+                response,
+                "{} threads: {} windows created, {} removed",
+                stats.threads, stats.created, stats.removed
+            )?;
+        }
+        Cmd::Ask {
+            question,
+            batch,
+            model,
+            limit,
+            max_context_tokens,
+            contact,
+            group,
+            no_parents,
+        } => {
+            let options = AskOptions {
+                ollama_url: ollama_url.to_string(),
+                model,
+                max_context_tokens,
+                include_parents: !no_parents,
+                filter: SearchFilter {
+                    limit,
+                    contact,
+                    group,
+                    ..Default::default()
+                },
+            };
+            let questions = match question {
+                Some(question) if !batch => vec![question],
+                _ => get_questions(),
+            };
+            for answer in ask_batch(vector_db, &questions, &options).await? {
+                writeln!(response, "{}", answer.to_text())?;
+            }
+        }
+        Cmd::Thread { id } => {
+            let chain = reply_chain(vector_db, id).await?;
+            if chain.is_empty() {
+                bail!("no stored message with id {id}");
+            }
+            let root = chain.iter().map(|message| message.depth).min().unwrap_or(0);
+            for message in &chain {
+                writeln!(response, "{}", message.to_line((message.depth - root) as usize))?;
+            }
+        }
+        Cmd::Reembed { model, batch_size } => {
+            let ReembedStats {
+                model,
+                dimension,
+                rows,
+            } = reembed(vector_db, embedder_config, &model, batch_size).await?;
+            writeln!(
+                response,
+                "{rows} rows re-embedded; {model} ({dimension} dimensions) is now active"
+            )?;
+        }
+        Cmd::EncryptExisting => {
+            let EncryptStats { rows, files } = encrypt_existing(vector_db).await?;
+            writeln!(response, "{rows} texts and {files} attachment files sealed")?;
+        }
+        Cmd::Export {
+            output,
+            format,
+            embeddings,
+            query,
+            limit,
+            filter,
+        } => {
+            let options = ExportOptions {
+                format,
+                filter,
+                embeddings,
+                query,
+                limit,
+            };
+            let count = export_messages(vector_db, &options, &output).await?;
+            writeln!(response, "{count} rows written to {}", output.display())?;
+        }
+        Cmd::Import { path, format } => {
+            let ImportedRows {
+                read,
+                inserted,
+                embedded,
+            } = import_messages(vector_db, format, &path).await?;
+            writeln!(
+                response,
+                "{read} rows read: {inserted} inserted, {embedded} embedded again"
+            )?;
+        }
+        Cmd::ImportDesktop {
+            path,
+            format,
+            self_aci,
+            desktop_attachments,
+        } => {
+            let ImportStats {
+                conversations,
+                messages,
+                skipped,
+            } = import_desktop(
+                vector_db,
+                format,
+                &path,
+                self_aci,
+                desktop_attachments.as_deref(),
+            )
+            .await?;
+            writeln!(
+                response,
+                "{conversations} conversations: {messages} messages read, {skipped} skipped"
+            )?;
+        }
+        Cmd::IndexHistory { restart } => {
+            let manager = Manager::load_registered(config_store).await?;
+            let HistoryStats { threads, messages } =
+                index_history(&manager, vector_db, restart).await?;
+            writeln!(response, "{threads} threads: {messages} messages read")?;
+        }
     }
 
     // println!("{}",response);
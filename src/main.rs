@@ -1,12 +1,62 @@
-use signal_vector_db::{entry_point, rag::sqlx::setup_database, types::Args};
+use clap::Parser;
+use dotenv::dotenv;
+use signal_vector_db::{
+    entry_point,
+    rag::{
+        attachments::AttachmentConfig, encryption::load_cipher, ingest::Ingest,
+        reembed::active_embedder, retention::spawn_reaper, sqlx::setup_database,
+        vector_db::VectorDb,
+    },
+    types::Args,
+};
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
-    let pg_pool = setup_database().await.unwrap();
-    
-    // Start receiving.
-    let args = Args::default();
-    _ = entry_point(args, &pg_pool).await.unwrap();
+    // Loaded before parsing so `.env` can provide defaults for flags
+    dotenv().ok();
+    let args = Args::parse();
+
+    let pool = setup_database(&args.embedder).await?;
+    // Probing the embedder sends a request, so only commands that embed wait for it;
+    // the others keep the configured one, unused
+    let embedder = match args.subcommand.embeds() {
+        true => active_embedder(&pool, &args.embedder).await?,
+        false => args.embedder.build(),
+    };
+    let cipher = load_cipher(&pool, &args.encryption, args.passphrase.as_deref()).await?;
+    let mut vector_db = VectorDb {
+        pool,
+        embedder,
+        chunking: args.chunking.clone(),
+        policy: args.policy.clone(),
+        retention: args.retention.clone(),
+        attachments: AttachmentConfig {
+            ollama_url: args.ollama_url.clone(),
+            ..args.attachments.clone()
+        },
+        cipher,
+        ingest: None,
+    };
+    let reaper = spawn_reaper(
+        vector_db.pool.clone(),
+        &args.retention,
+        vector_db.attachments.dir.clone(),
+    );
+    let (ingest, ingest_worker) = Ingest::spawn(vector_db.clone(), &args.ingest);
+    vector_db.ingest = Some(ingest);
+
+    let response = entry_point(args, &vector_db).await;
+    // Closing the queue lets the worker store what is left, then exit
+    drop(vector_db);
+    ingest_worker.await?;
+    if let Some(reaper) = reaper {
+        reaper.abort();
+    }
+
+    let response = response?;
+    if !response.is_empty() {
+        println!("{response}");
+    }
 
     Ok(())
 }
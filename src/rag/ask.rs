@@ -0,0 +1,344 @@
+use chrono::{DateTime, Utc};
+
+use crate::rag::dataframes::num_tokens_from_str;
+use crate::rag::generate::{generate_from_ollama, DEFAULT_OLLAMA_URL};
+use crate::rag::prompt_template::llama3_with_system;
+use crate::rag::search::{search_messages, SearchFilter, SearchResult};
+use crate::rag::threads::find_message;
+use crate::rag::vector_db::VectorDb;
+
+const SYSTEM_PROMPT: &str = "You answer questions about the user's Signal conversations.
+Only use the numbered messages provided as context. Cite the messages you rely on with their number in square brackets, e.g. [2].
+If the context does not contain the answer, say that you don't know.
+";
+
+#[derive(Clone, Debug)]
+pub struct AskOptions {
+    /// Base URL of the Ollama server generating the answer.
+    pub ollama_url: String,
+    pub model: String,
+    /// Upper bound for the tokens spent on retrieved messages.
+    pub max_context_tokens: usize,
+    /// Adds the message a matching reply quotes, so the model sees what it answers.
+    pub include_parents: bool,
+    pub filter: SearchFilter,
+}
+
+impl Default for AskOptions {
+    fn default() -> Self {
+        AskOptions {
+            ollama_url: String::from(DEFAULT_OLLAMA_URL),
+            model: String::from("llama3.2"),
+            max_context_tokens: 2048,
+            include_parents: true,
+            filter: SearchFilter::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Citation {
+    /// Number used for this message in the prompt, e.g. `[1]`.
+    pub index: usize,
+    /// Row id in the `embeddings` table.
+    pub id: i64,
+    /// Author and sent timestamp, which identify the message in Signal.
+    pub sender_aci: Option<String>,
+    pub sent_at: Option<i64>,
+    pub direction: Option<String>,
+    pub contact: Option<String>,
+    pub group_name: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub body: String,
+    /// Citation number of the message this one replies to.
+    pub reply_to: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Answer {
+    pub question: String,
+    pub answer: String,
+    pub citations: Vec<Citation>,
+}
+
+impl Answer {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Q: {}\nA: {}\n", self.question, self.answer);
+        if !self.citations.is_empty() {
+            text.push_str("Sources:\n");
+        }
+        for citation in &self.citations {
+            text.push_str(&format!(
+                "  [{}] #{} {} {}: {}\n",
+                citation.index,
+                citation.id,
+                citation.timestamp.format("%Y-%m-%d %H:%M"),
+                citation
+                    .group_name
+                    .clone()
+                    .or(citation.contact.clone())
+                    .unwrap_or_default(),
+                citation.body,
+            ));
+        }
+        text
+    }
+}
+
+/// Retrieves the messages closest to `question` and lets the model answer from them.
+pub async fn ask(
+    vector_db: &VectorDb,
+    question: &str,
+    options: &AskOptions,
+) -> anyhow::Result<Answer> {
+    let mut results = search_messages(vector_db, question, &options.filter).await?;
+    if options.include_parents {
+        results = with_parents(vector_db, results).await?;
+    }
+    let citations = pack_context(results, options.max_context_tokens);
+
+    let context = citations
+        .iter()
+        .map(format_citation)
+        .collect::<Vec<String>>()
+        .join("\n");
+    let user_message = format!("Messages:\n{context}\n\nQuestion: {question}");
+    let prompt = llama3_with_system(SYSTEM_PROMPT, &user_message);
+
+    let answer = generate_from_ollama(&options.ollama_url, &options.model, &prompt).await?;
+
+    Ok(Answer {
+        question: question.to_string(),
+        answer,
+        citations,
+    })
+}
+
+/// Runs every question in order, e.g. the canned list from `questions::get_questions`.
+pub async fn ask_batch(
+    vector_db: &VectorDb,
+    questions: &[String],
+    options: &AskOptions,
+) -> anyhow::Result<Vec<Answer>> {
+    let mut answers = Vec::with_capacity(questions.len());
+    for question in questions {
+        answers.push(ask(vector_db, question, options).await?);
+    }
+    Ok(answers)
+}
+
+// Puts the message each reply quotes right before it, unless it was found already.
+// The parent must be in the reply's chat, so a forged quote cannot pull in another one.
+async fn with_parents(
+    vector_db: &VectorDb,
+    results: Vec<SearchResult>,
+) -> anyhow::Result<Vec<SearchResult>> {
+    let mut with_parents: Vec<SearchResult> = Vec::with_capacity(results.len());
+    for result in &results {
+        if let (Some(sender_aci), Some(sent_at), Some(thread_id)) = (
+            &result.reply_to_sender_aci,
+            result.reply_to_sent_at,
+            result.thread_id.as_deref(),
+        ) {
+            let known = results.iter().chain(&with_parents).any(|other| {
+                other.sender_aci.as_ref() == Some(sender_aci) && other.sent_at == Some(sent_at)
+            });
+            if !known {
+                if let Some(parent) =
+                    find_message(vector_db, thread_id, sender_aci, sent_at).await?
+                {
+                    with_parents.push(SearchResult {
+                        id: parent.id,
+                        body: parent.body,
+                        direction: parent.direction,
+                        contact: parent.contact,
+                        group_name: parent.group_name,
+                        sender_aci: parent.sender_aci,
+                        thread_id: result.thread_id.clone(),
+                        sent_at: parent.sent_at,
+                        reply_to_sender_aci: parent.reply_to_sender_aci,
+                        reply_to_sent_at: parent.reply_to_sent_at,
+                        chunk_index: 0,
+                        chunk_count: 1,
+                        attachment: None,
+                        source: String::from("message"),
+                        superseded: false,
+                        reactions: 0,
+                        timestamp: parent.timestamp,
+                        similarity: 0.0,
+                    });
+                }
+            }
+        }
+        with_parents.push(result.clone());
+    }
+    Ok(with_parents)
+}
+
+// Keeps the most similar messages first and stops once the budget is used up.
+fn pack_context(results: Vec<SearchResult>, max_context_tokens: usize) -> Vec<Citation> {
+    let mut citations = Vec::new();
+    let mut used_tokens = 0;
+
+    for result in results {
+        let reply_to = citations
+            .iter()
+            .find(|parent: &&Citation| {
+                parent.sender_aci.is_some()
+                    && parent.sender_aci == result.reply_to_sender_aci
+                    && parent.sent_at == result.reply_to_sent_at
+            })
+            .map(|parent| parent.index);
+        let citation = Citation {
+            index: citations.len() + 1,
+            id: result.id,
+            sender_aci: result.sender_aci,
+            sent_at: result.sent_at,
+            direction: result.direction,
+            contact: result.contact,
+            group_name: result.group_name,
+            timestamp: result.timestamp,
+            body: result.body.unwrap_or_default(),
+            reply_to,
+        };
+        let tokens = num_tokens_from_str(&format_citation(&citation));
+        if used_tokens + tokens > max_context_tokens {
+            break;
+        }
+        used_tokens += tokens;
+        citations.push(citation);
+    }
+
+    citations
+}
+
+fn format_citation(citation: &Citation) -> String {
+    let sent = citation.direction.as_deref() == Some("to");
+    let chat = match (&citation.group_name, &citation.contact) {
+        (Some(group), Some(contact)) => format!("{contact} in {group}"),
+        (Some(group), None) => format!("me in {group}"),
+        (None, Some(contact)) if sent => format!("me to {contact}"),
+        (None, Some(contact)) => contact.clone(),
+        (None, None) => String::new(),
+    };
+    let reply = match citation.reply_to {
+        Some(parent) => format!(" (reply to [{parent}])"),
+        None => String::new(),
+    };
+    format!(
+        "[{}] {} {}{}: {}",
+        citation.index,
+        citation.timestamp.format("%Y-%m-%d %H:%M"),
+        chat,
+        reply,
+        citation.body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn result(id: i64, sender_aci: &str, sent_at: i64, body: &str) -> SearchResult {
+        SearchResult {
+            id,
+            body: Some(body.to_string()),
+            direction: Some(String::from("from")),
+            contact: Some(String::from("Alice")),
+            group_name: None,
+            sender_aci: Some(sender_aci.to_string()),
+            thread_id: Some(String::from("aci-alice")),
+            sent_at: Some(sent_at),
+            reply_to_sender_aci: None,
+            reply_to_sent_at: None,
+            chunk_index: 0,
+            chunk_count: 1,
+            attachment: None,
+            source: String::from("message"),
+            superseded: false,
+            reactions: 0,
+            timestamp: Utc.timestamp_millis_opt(sent_at).unwrap(),
+            similarity: 0.9,
+        }
+    }
+
+    fn reply(mut result: SearchResult, sender_aci: &str, sent_at: i64) -> SearchResult {
+        result.reply_to_sender_aci = Some(sender_aci.to_string());
+        result.reply_to_sent_at = Some(sent_at);
+        result
+    }
+
+    #[test]
+    fn context_stops_at_the_token_budget() {
+        let results = vec![
+            result(1, "aci-alice", 1_000, "first message"),
+            result(2, "aci-alice", 2_000, "second message"),
+            result(3, "aci-alice", 3_000, "third message"),
+        ];
+        let everything = pack_context(results.clone(), usize::MAX);
+        assert_eq!(everything.len(), 3);
+
+        let budget = everything[..2]
+            .iter()
+            .map(|citation| num_tokens_from_str(&format_citation(citation)))
+            .sum();
+        let packed = pack_context(results.clone(), budget);
+        assert_eq!(
+            packed
+                .iter()
+                .map(|citation| citation.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(pack_context(results.clone(), budget - 1).len(), 1);
+        assert!(pack_context(results, 0).is_empty());
+    }
+
+    #[test]
+    fn replies_cite_their_parent() {
+        let results = vec![
+            result(10, "aci-alice", 1_000, "dinner?"),
+            result(11, "aci-bob", 2_000, "unrelated"),
+            reply(result(12, "aci-bob", 3_000, "sure"), "aci-alice", 1_000),
+            // Same timestamp, other author
+            reply(result(13, "aci-bob", 4_000, "no"), "aci-carol", 1_000),
+            reply(result(14, "aci-alice", 5_000, "great"), "aci-bob", 3_000),
+        ];
+        let citations = pack_context(results, usize::MAX);
+        let numbers: Vec<_> = citations
+            .iter()
+            .map(|citation| (citation.index, citation.reply_to))
+            .collect();
+        assert_eq!(
+            numbers,
+            vec![(1, None), (2, None), (3, Some(1)), (4, None), (5, Some(3))]
+        );
+        assert!(format_citation(&citations[2]).contains(" (reply to [1]): sure"));
+        assert!(!format_citation(&citations[3]).contains("reply to"));
+    }
+
+    #[test]
+    fn citations_name_the_chat() {
+        let incoming = pack_context(vec![result(1, "aci-alice", 0, "hi")], usize::MAX);
+        assert_eq!(
+            format_citation(&incoming[0]),
+            "[1] 1970-01-01 00:00 Alice: hi"
+        );
+
+        let mut sent = result(1, "aci-me", 0, "hi");
+        sent.direction = Some(String::from("to"));
+        let mut group = result(2, "aci-alice", 0, "yo");
+        group.group_name = Some(String::from("Trip"));
+        let citations = pack_context(vec![sent, group], usize::MAX);
+        assert_eq!(
+            format_citation(&citations[0]),
+            "[1] 1970-01-01 00:00 me to Alice: hi"
+        );
+        assert_eq!(
+            format_citation(&citations[1]),
+            "[2] 1970-01-01 00:00 Alice in Trip: yo"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use tracing::info;
+
+use crate::rag::encryption::{file_is_sealed, Cipher};
+
+/// What is known about an attachment besides its contents.
+#[derive(Clone, Debug, Default)]
+pub struct AttachmentMeta {
+    pub mime_type: Option<String>,
+    /// Name the sender gave the file.
+    pub file_name: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub voice_note: bool,
+    /// Identity of the message the attachment came with.
+    pub sender_aci: Option<String>,
+    pub sent_at: Option<u64>,
+}
+
+/// Where contents with `digest` are stored under the attachments root: sharded by the
+/// first two hex digits, with an extension so the type can still be told from the name.
+pub fn content_path(digest: &str, extension: &str) -> PathBuf {
+    PathBuf::from(&digest[..2]).join(format!("{digest}.{extension}"))
+}
+
+/// Stores `data` under `root` by its SHA-256 unless the same contents are already there,
+/// records it in `attachments` and returns its path relative to `root`. With a cipher the
+/// file is sealed; the digest is still that of the plaintext, so duplicates are found, and
+/// a copy stored before encryption was set up is replaced by a sealed one.
+pub async fn save_attachment(
+    pool: &Pool<Postgres>,
+    cipher: Option<&Cipher>,
+    root: &Path,
+    data: &[u8],
+    meta: &AttachmentMeta,
+) -> anyhow::Result<String> {
+    let digest = hex::encode(Sha256::digest(data));
+    let extension = extension(meta);
+    let relative = content_path(&digest, &extension);
+    let path = root.join(&relative);
+
+    let stored = tokio::fs::try_exists(&path).await.unwrap_or(false);
+    if stored && (cipher.is_none() || file_is_sealed(&path).await?) {
+        info!(path =% path.display(), "attachment already stored");
+    } else {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let contents = match cipher {
+            Some(cipher) => cipher.seal(data),
+            None => data.to_vec(),
+        };
+        replace_file(&path, &contents).await?;
+        info!(path =% path.display(), "saved attachment");
+    }
+
+    let relative = relative.to_string_lossy().into_owned();
+    sqlx::query(
+        r#"
+        INSERT INTO attachments
+            (sha256, path, mime_type, size, file_name, width, height, voice_note, sender_aci, sent_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(&digest)
+    .bind(&relative)
+    .bind(&meta.mime_type)
+    .bind(data.len() as i64)
+    .bind(&meta.file_name)
+    .bind(meta.width.map(|width| width as i32))
+    .bind(meta.height.map(|height| height as i32))
+    .bind(meta.voice_note)
+    .bind(&meta.sender_aci)
+    .bind(meta.sent_at.map(|sent_at| sent_at as i64))
+    .execute(pool)
+    .await?;
+    Ok(relative)
+}
+
+/// Writes `contents` to `path`, renamed into place so a crash never leaves a partial file
+/// under the name.
+pub async fn replace_file(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    tokio::fs::write(&partial, contents)
+        .await
+        .with_context(|| format!("failed to write {}", partial.display()))?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// Extension of the sender's file name, else the usual one for the mime type.
+fn extension(meta: &AttachmentMeta) -> String {
+    let from_name = meta
+        .file_name
+        .as_deref()
+        .and_then(|name| Path::new(name).extension()?.to_str())
+        .filter(|extension| extension.len() <= 8 && extension.chars().all(char::is_alphanumeric));
+    let from_mime = || {
+        let mime = meta.mime_type.as_deref()?;
+        mime_guess::get_mime_extensions_str(mime)?.first().copied()
+    };
+    from_name
+        .or_else(from_mime)
+        .unwrap_or("bin")
+        .to_ascii_lowercase()
+}
@@ -0,0 +1,614 @@
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{bail, Context as _};
+use clap::Args;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+use crate::rag::dataframes::{process_dataframe, SignalMessageWithEmbedding};
+use crate::rag::encryption::Cipher;
+use crate::rag::generate::{describe_image_with_ollama, DEFAULT_OLLAMA_URL};
+use crate::rag::vector_db::VectorDb;
+use crate::signal::attachments_dir::ATTACHMENTS_DIR;
+use crate::signal::format_message::MessageKind;
+use crate::signal::process_incoming_message::ProcessedMessage;
+
+#[derive(Args, Clone, Debug)]
+pub struct AttachmentConfig {
+    #[clap(
+        long = "attachments-dir",
+        env = "ATTACHMENTS_DIR",
+        default_value = ATTACHMENTS_DIR,
+        help = "Where attachments are stored, by the SHA-256 of their contents"
+    )]
+    pub dir: PathBuf,
+    #[clap(
+        long = "no-attachment-text",
+        action = clap::ArgAction::SetFalse,
+        help = "Do not extract and embed the text of received attachments"
+    )]
+    pub extract_text: bool,
+    #[clap(
+        long = "attachment-max-bytes",
+        default_value_t = 20 * 1024 * 1024,
+        help = "Attachments larger than this are not read"
+    )]
+    pub max_bytes: u64,
+    #[clap(
+        long = "ocr",
+        help = "Read the text in image attachments with tesseract"
+    )]
+    pub ocr: bool,
+    #[clap(
+        long = "tesseract",
+        env = "TESSERACT",
+        default_value = "tesseract",
+        help = "Tesseract binary used by --ocr"
+    )]
+    pub tesseract: String,
+    #[clap(
+        long = "ocr-languages",
+        default_value = "eng",
+        help = "Tesseract languages, e.g. eng+deu"
+    )]
+    pub ocr_languages: String,
+    #[clap(
+        long = "caption-model",
+        help = "Ollama model with vision, e.g. llava, that describes image attachments"
+    )]
+    pub caption_model: Option<String>,
+    #[clap(
+        long = "whisper-model",
+        env = "WHISPER_MODEL",
+        help = "whisper.cpp model file (ggml) that transcribes voice notes"
+    )]
+    pub whisper_model: Option<PathBuf>,
+    #[clap(
+        long = "whisper",
+        env = "WHISPER",
+        default_value = "whisper-cli",
+        help = "whisper.cpp command line binary"
+    )]
+    pub whisper: String,
+    #[clap(
+        long = "whisper-threads",
+        default_value_t = 4,
+        help = "CPU threads used for transcription"
+    )]
+    pub whisper_threads: usize,
+    #[clap(
+        long = "transcribe-language",
+        default_value = "auto",
+        help = "Language spoken in voice notes, e.g. en, or auto to detect it"
+    )]
+    pub transcribe_language: String,
+    #[clap(
+        long = "ffmpeg",
+        env = "FFMPEG",
+        default_value = "ffmpeg",
+        help = "ffmpeg binary that converts voice notes for whisper"
+    )]
+    pub ffmpeg: String,
+    /// Ollama server captioning images, from the global `--ollama-url`.
+    #[clap(skip = String::from(DEFAULT_OLLAMA_URL))]
+    pub ollama_url: String,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            dir: PathBuf::from(ATTACHMENTS_DIR),
+            extract_text: true,
+            max_bytes: 20 * 1024 * 1024,
+            ocr: false,
+            tesseract: String::from("tesseract"),
+            ocr_languages: String::from("eng"),
+            caption_model: None,
+            whisper_model: None,
+            whisper: String::from("whisper-cli"),
+            whisper_threads: 4,
+            transcribe_language: String::from("auto"),
+            ffmpeg: String::from("ffmpeg"),
+            ollama_url: String::from(DEFAULT_OLLAMA_URL),
+        }
+    }
+}
+
+const CAPTION_PROMPT: &str = "Describe this image in two or three sentences: what it shows, \
+    where it seems to be, and anything written on it.";
+
+/// Text read from one attachment.
+#[derive(Clone, Debug)]
+pub struct AttachmentText {
+    pub file_name: String,
+    /// Format the text was read from, e.g. `pdf`, or `ocr` and `caption` for images;
+    /// stored as the rows' `source`.
+    pub source: &'static str,
+    pub text: String,
+}
+
+/// How search results and transcripts name text that came from `attachment`.
+pub fn describe_source(source: &str, attachment: &str) -> String {
+    match source {
+        "ocr" => format!("text in {attachment}"),
+        "caption" => format!("caption of {attachment}"),
+        _ => format!("from {attachment}"),
+    }
+}
+
+/// Chunks and embeds the text of the attachments of `messages` like message bodies,
+/// as rows linked to the message each attachment came with.
+pub async fn attachment_rows(
+    vector_db: &VectorDb,
+    messages: &[ProcessedMessage],
+) -> anyhow::Result<Vec<SignalMessageWithEmbedding>> {
+    let mut rows = vec![];
+    if !vector_db.attachments.extract_text || !vector_db.policy.persists(MessageKind::Extracted) {
+        return Ok(rows);
+    }
+    for message in messages {
+        for file_name in message.attachments.iter().flatten() {
+            let texts =
+                extract_attachment(&vector_db.attachments, vector_db.cipher.as_ref(), file_name)
+                    .await;
+            for extracted in texts {
+                rows.extend(text_rows(vector_db, message, extracted).await?);
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Chunks and embeds one extracted text as rows of `message`.
+async fn text_rows(
+    vector_db: &VectorDb,
+    message: &ProcessedMessage,
+    extracted: AttachmentText,
+) -> anyhow::Result<Vec<SignalMessageWithEmbedding>> {
+    let mut chunks = process_dataframe(
+        &vec![extracted_message(message, &extracted)],
+        &*vector_db.embedder,
+        &vector_db.chunking,
+        &vector_db.policy,
+    )
+    .await
+    .with_context(|| {
+        format!(
+            "failed to embed the {} of {}",
+            extracted.source, extracted.file_name
+        )
+    })?;
+    for chunk in &mut chunks {
+        chunk.source = extracted.source.to_string();
+        chunk.attachment = extracted.file_name.clone();
+    }
+    Ok(chunks)
+}
+
+/// The message `extracted` is stored as. It is embedded by its own kind, not that of
+/// the message it came with, which often has no text; it is not a reply itself.
+fn extracted_message(message: &ProcessedMessage, extracted: &AttachmentText) -> ProcessedMessage {
+    ProcessedMessage {
+        kind: MessageKind::Extracted,
+        body: Some(extracted.text.clone()),
+        attachments: None,
+        quote_author_aci: None,
+        quote_sent_at: None,
+        voice_note: None,
+        language: None,
+        ..message.clone()
+    }
+}
+
+/// Reads the texts of a saved attachment: its contents for documents, the text in it
+/// and a caption for images, as configured. Empty when it has none or cannot be read.
+pub async fn extract_attachment(
+    config: &AttachmentConfig,
+    cipher: Option<&Cipher>,
+    file_name: &str,
+) -> Vec<AttachmentText> {
+    let path = config.dir.join(file_name);
+    let Some(format) = text_format(&path) else {
+        return vec![];
+    };
+    if format == "image" && !config.ocr && config.caption_model.is_none() {
+        return vec![];
+    }
+    let Some(bytes) = read_attachment(config, cipher, &path, file_name).await else {
+        return vec![];
+    };
+
+    let mut texts = vec![];
+    if format == "image" {
+        if config.ocr {
+            match ocr(config, &bytes).await {
+                Ok(text) => texts.push(("ocr", text)),
+                Err(error) => warn!(file_name, %error, "failed to read text in image"),
+            }
+        }
+        if let Some(model) = &config.caption_model {
+            let url = &config.ollama_url;
+            match describe_image_with_ollama(url, model, CAPTION_PROMPT, &bytes).await {
+                Ok(caption) => texts.push(("caption", caption)),
+                Err(error) => warn!(file_name, %error, "failed to caption image"),
+            }
+        }
+    } else {
+        // Parsers are synchronous, and may panic on malformed documents
+        match tokio::task::spawn_blocking(move || extract_text(format, &bytes)).await {
+            Ok(Ok(text)) => texts.push((format, text)),
+            Ok(Err(error)) => warn!(file_name, %error, "failed to extract attachment text"),
+            Err(error) => warn!(file_name, %error, "attachment text extraction failed"),
+        }
+    }
+
+    texts
+        .into_iter()
+        .filter(|(_, text)| !text.trim().is_empty())
+        .map(|(source, text)| AttachmentText {
+            file_name: file_name.to_string(),
+            source,
+            text: text.trim().to_string(),
+        })
+        .collect()
+}
+
+async fn read_attachment(
+    config: &AttachmentConfig,
+    cipher: Option<&Cipher>,
+    path: &Path,
+    file_name: &str,
+) -> Option<Vec<u8>> {
+    // Imported and backfilled messages often come without their files
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(error) => {
+            debug!(file_name, %error, "attachment not available");
+            return None;
+        }
+    };
+    if metadata.len() > config.max_bytes {
+        debug!(
+            file_name,
+            bytes = metadata.len(),
+            "attachment too large to read"
+        );
+        return None;
+    }
+    let read = async {
+        let bytes = tokio::fs::read(&path).await?;
+        match cipher {
+            Some(cipher) => cipher.open(&bytes),
+            None => Ok(bytes),
+        }
+    };
+    match read.await {
+        Ok(bytes) => Some(bytes),
+        Err(error) => {
+            warn!(file_name, %error, "failed to read attachment");
+            None
+        }
+    }
+}
+
+/// Runs tesseract on an image and returns the text it found. The image is piped in,
+/// so an encrypted attachment is never written out in the clear.
+async fn ocr(config: &AttachmentConfig, image: &[u8]) -> anyhow::Result<String> {
+    let mut child = Command::new(&config.tesseract)
+        .args(["stdin", "stdout"])
+        .args(["-l", &config.ocr_languages])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {}", config.tesseract))?;
+    let mut stdin = child.stdin.take().context("tesseract has no stdin")?;
+    stdin.write_all(image).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        bail!(
+            "tesseract exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Which extractor handles `path`, from its extension.
+fn text_format(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "pdf" => Some("pdf"),
+        "docx" => Some("docx"),
+        "odt" => Some("odt"),
+        "html" | "htm" | "xhtml" => Some("html"),
+        _ => {
+            let mime = mime_guess::from_ext(&extension).first()?;
+            match mime.type_() {
+                mime_guess::mime::TEXT => Some("text"),
+                mime_guess::mime::IMAGE => Some("image"),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn extract_text(format: &str, bytes: &[u8]) -> anyhow::Result<String> {
+    Ok(match format {
+        "pdf" => pdf_extract::extract_text_from_mem(bytes)?,
+        "docx" => strip_markup(&zip_entry(bytes, "word/document.xml")?),
+        "odt" => strip_markup(&zip_entry(bytes, "content.xml")?),
+        "html" => strip_markup(&String::from_utf8_lossy(bytes)),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    })
+}
+
+/// Reads one file of a zip container such as an Office document.
+fn zip_entry(bytes: &[u8], name: &str) -> anyhow::Result<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("no {name} in document"))?;
+    let mut xml = String::new();
+    entry.read_to_string(&mut xml)?;
+    Ok(xml)
+}
+
+// Tags that end a line of text, in HTML, Word and OpenDocument markup
+const LINE_TAGS: [&str; 17] = [
+    "p",
+    "br",
+    "div",
+    "li",
+    "tr",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "w:p",
+    "w:br",
+    "w:cr",
+    "text:p",
+    "text:h",
+    "text:line-break",
+];
+
+// Tags whose content is not text
+const SKIPPED_TAGS: [&str; 2] = ["script", "style"];
+
+/// Drops the tags of HTML or XML markup, keeping line breaks at paragraphs and decoding
+/// entities.
+fn strip_markup(markup: &str) -> String {
+    let mut text = String::new();
+    let mut skipping: Option<&str> = None;
+    let mut rest = markup;
+    while let Some(start) = rest.find('<') {
+        if let Some(skipped) = skipping.take() {
+            // Scripts may compare with `<`, so only their closing tag ends them
+            let Some(close) = rest.to_ascii_lowercase().find(&format!("</{skipped}")) else {
+                rest = "";
+                break;
+            };
+            rest = &rest[close..];
+            continue;
+        }
+        text.push_str(&decode_entities(&rest[..start]));
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if !closing && !tag.ends_with('/') {
+            skipping = SKIPPED_TAGS.into_iter().find(|skipped| *skipped == name);
+            if skipping.is_some() {
+                continue;
+            }
+        }
+        if LINE_TAGS.contains(&name.as_str()) && !text.ends_with('\n') {
+            text.push('\n');
+        } else if name == "w:tab" || name == "text:tab" {
+            text.push('\t');
+        }
+    }
+    if skipping.is_none() {
+        text.push_str(&decode_entities(rest));
+    }
+
+    // Markup indentation leaves runs of blank lines
+    let mut lines: Vec<&str> = vec![];
+    for line in text.lines().map(str::trim) {
+        if !line.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(line);
+        }
+    }
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use super::*;
+
+    #[test]
+    fn tags_are_stripped() {
+        let cases = [
+            ("plain text", "plain text"),
+            (
+                "<b>bold</b> and <i class=\"x\">italic</i>",
+                "bold and italic",
+            ),
+            ("<p>one</p><p>two</p>", "one\ntwo"),
+            ("one<br>two<br/>three<BR />four", "one\ntwo\nthree\nfour"),
+            ("<ul>\n  <li>a</li>\n\n\n  <li>b</li>\n</ul>", "a\n\nb"),
+            ("<h1>Title</h1>body", "Title\nbody"),
+            (
+                "<w:p><w:r><w:t>a</w:t><w:tab/><w:t>b</w:t></w:r></w:p>",
+                "a\tb",
+            ),
+            (
+                "<text:p>one</text:p><text:p>two<text:line-break/>three</text:p>",
+                "one\ntwo\nthree",
+            ),
+            ("Tom &amp; Jerry &lt;3", "Tom & Jerry <3"),
+            ("unclosed <b", "unclosed"),
+        ];
+        for (markup, text) in cases {
+            assert_eq!(strip_markup(markup), text, "{markup}");
+        }
+    }
+
+    #[test]
+    fn scripts_and_styles_are_skipped() {
+        let cases = [
+            ("<script>if (a < b) alert(1)</script>text", "text"),
+            (
+                "<style type=\"text/css\">p { color: red }</style><p>text</p>",
+                "text",
+            ),
+            ("a<SCRIPT>x</SCRIPT>b", "ab"),
+            ("<script src=\"a.js\"/>text", "text"),
+            ("text<script>never closed", "text"),
+        ];
+        for (markup, text) in cases {
+            assert_eq!(strip_markup(markup), text, "{markup}");
+        }
+    }
+
+    #[test]
+    fn entities_are_decoded() {
+        let cases = [
+            ("&amp;&lt;&gt;&quot;&apos;", "&<>\"'"),
+            ("a&nbsp;b", "a b"),
+            ("&#233;t&#233;", "été"),
+            ("&#x1F642; &#X41;", "🙂 A"),
+            ("&unknown; stays", "&unknown; stays"),
+            ("&#xZZ; &#99999999;", "&#xZZ; &#99999999;"),
+            ("fish & chips", "fish & chips"),
+            ("&amp", "&amp"),
+            ("&averyverylongname; &amp;", "&averyverylongname; &"),
+        ];
+        for (text, decoded) in cases {
+            assert_eq!(decode_entities(text), decoded, "{text}");
+        }
+    }
+
+    #[test]
+    fn formats_follow_the_extension() {
+        let cases = [
+            ("report.pdf", Some("pdf")),
+            ("REPORT.PDF", Some("pdf")),
+            ("letter.docx", Some("docx")),
+            ("letter.odt", Some("odt")),
+            ("page.htm", Some("html")),
+            ("page.xhtml", Some("html")),
+            ("notes.txt", Some("text")),
+            ("data.csv", Some("text")),
+            ("photo.jpg", Some("image")),
+            ("screenshot.PNG", Some("image")),
+            ("archive.zip", None),
+            ("song.mp3", None),
+            ("no_extension", None),
+        ];
+        for (path, format) in cases {
+            assert_eq!(text_format(Path::new(path)), format, "{path}");
+        }
+    }
+
+    fn zip(name: &str, content: &str) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        writer
+            .start_file(name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn text_is_extracted_by_format() {
+        let docx = zip(
+            "word/document.xml",
+            "<w:document><w:body><w:p><w:r><w:t>Hello</w:t></w:r></w:p><w:p><w:r><w:t>World</w:t></w:r></w:p></w:body></w:document>",
+        );
+        let odt = zip(
+            "content.xml",
+            "<office:text><text:h>Title</text:h><text:p>Body &amp; more</text:p></office:text>",
+        );
+        let cases: [(&str, &[u8], &str); 4] = [
+            ("docx", &docx, "Hello\nWorld"),
+            ("odt", &odt, "Title\nBody & more"),
+            ("html", b"<p>Hi &amp; bye</p>", "Hi & bye"),
+            ("text", b"<p>kept as is</p>", "<p>kept as is</p>"),
+        ];
+        for (format, bytes, text) in cases {
+            assert_eq!(extract_text(format, bytes).unwrap(), text, "{format}");
+        }
+    }
+
+    #[test]
+    fn documents_without_their_text_entry_fail() {
+        let odt = zip("content.xml", "<text:p>odt</text:p>");
+        assert!(extract_text("docx", &odt).is_err());
+        assert!(extract_text("docx", b"not a zip").is_err());
+    }
+}
@@ -1,17 +1,46 @@
+use clap::Args;
 use pgvector::Vector;
 use serde::{Deserialize, Serialize};
-use reqwest::Client;
-use serde_json::{json, Value};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SignalMessageWithEmbedding {
+    /// Text of this chunk only.
     pub body: String,
     pub direction: String,
     pub contact: Option<String>,
     pub group_name: Option<String>,
     pub attachments: Option<Vec<String>>,
+    /// Tokens in this chunk.
     pub tokens: i32,
+    /// Empty when the policy keeps this kind of message without a vector.
     pub embedding: Vec<f32>,
+    /// Model `embedding` was made with, `None` without one.
+    pub embedding_model: Option<String>,
+    /// Signal sent timestamp in milliseconds; with `sender_aci` it identifies the message
+    /// every chunk belongs to.
+    pub sent_at: Option<i64>,
+    pub sender_aci: Option<String>,
+    /// Contact uuid or hex group master key.
+    pub thread_id: Option<String>,
+    pub server_guid: Option<String>,
+    /// `MessageKind` name, e.g. `text`.
+    pub kind: String,
+    pub chunk_index: i32,
+    pub chunk_count: i32,
+    /// Character range of this chunk in the full message body.
+    pub char_start: Option<i32>,
+    pub char_end: Option<i32>,
+    /// When a disappearing message is due to be removed, in milliseconds.
+    pub expires_at: Option<i64>,
+    /// Identity of the message this one replies to.
+    pub reply_to_sender_aci: Option<String>,
+    pub reply_to_sent_at: Option<i64>,
+    /// How the text was obtained: `message` for the body, otherwise from an attachment.
+    pub source: String,
+    /// File name of the attachment the text comes from, empty for the body.
+    pub attachment: String,
+    /// Language detected in a transcribed voice note.
+    pub language: Option<String>,
 }
 
 #[derive(Clone, Debug, FromRow, Encode)]
@@ -22,20 +51,144 @@ pub struct SignalMessageWithVector {
     pub group_name: Option<String>,
     pub attachments: Option<Vec<String>>,
     pub tokens: i32,
-    pub embedding: Vector,
+    pub embedding: Option<Vector>,
+    pub embedding_model: Option<String>,
+    pub sent_at: Option<i64>,
+    pub sender_aci: Option<String>,
+    pub thread_id: Option<String>,
+    pub server_guid: Option<String>,
+    pub kind: Option<String>,
+    pub chunk_index: i32,
+    pub chunk_count: i32,
+    pub char_start: Option<i32>,
+    pub char_end: Option<i32>,
+    pub expires_at: Option<i64>,
+    pub reply_to_sender_aci: Option<String>,
+    pub reply_to_sent_at: Option<i64>,
+    pub source: Option<String>,
+    pub attachment: Option<String>,
+    pub language: Option<String>,
 }
+use std::sync::OnceLock;
+
 use sqlx::{Encode, FromRow};
-use tiktoken_rs::cl100k_base;
+use tiktoken_rs::{cl100k_base, CoreBPE};
 
+use crate::rag::embedder::Embedder;
+use crate::rag::ingest::StoragePolicy;
 use crate::signal::process_incoming_message::ProcessedMessage;
 
+#[derive(Args, Clone, Debug)]
+pub struct ChunkConfig {
+    #[clap(
+        long = "chunk-tokens",
+        default_value_t = 512,
+        help = "Messages longer than this many tokens are split into chunks"
+    )]
+    pub size: usize,
+    #[clap(
+        long = "chunk-overlap",
+        default_value_t = 64,
+        help = "Tokens repeated at the start of each following chunk"
+    )]
+    pub overlap: usize,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        ChunkConfig {
+            size: 512,
+            overlap: 64,
+        }
+    }
+}
+
+/// A piece of a message body with its position in the full body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub text: String,
+    pub tokens: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+}
+
+/// The tokenizer chunks are measured with, built once: building it parses the whole
+/// vocabulary.
+pub fn tokenizer() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| cl100k_base().expect("cl100k_base vocabulary is bundled"))
+}
+
 // Helper function to calculate number of tokens
-fn num_tokens_from_str(string: &str) -> usize {
+pub fn num_tokens_from_str(string: &str) -> usize {
     if string.is_empty() {
         return 0;
     }
-    let bpe = cl100k_base().unwrap();
-    bpe.encode_with_special_tokens(string).len()
+    tokenizer().encode_with_special_tokens(string).len()
+}
+
+/// Splits `text` into windows of at most `config.size` tokens, each starting
+/// `config.overlap` tokens before the previous one ended.
+///
+/// Boundaries fall between tokens, moved forward when a token ends inside a
+/// multi-byte character, so every chunk is a slice of `text`. Empty text has no chunks.
+pub fn chunk_text(text: &str, config: &ChunkConfig) -> Vec<Chunk> {
+    if text.is_empty() {
+        return vec![];
+    }
+    let bpe = tokenizer();
+    let tokens = bpe.encode_with_special_tokens(text);
+    let size = config.size.max(1);
+
+    if tokens.len() <= size {
+        return vec![Chunk {
+            text: text.to_string(),
+            tokens: tokens.len(),
+            char_start: 0,
+            char_end: text.chars().count(),
+        }];
+    }
+
+    // Byte offset at which each token starts, plus the end of the text
+    let mut offsets = Vec::with_capacity(tokens.len() + 1);
+    let mut offset = 0;
+    offsets.push(offset);
+    for token_bytes in bpe._decode_native_and_split(tokens.clone()) {
+        offset += token_bytes.len();
+        offsets.push(offset.min(text.len()));
+    }
+    let to_char_boundary = |mut byte: usize| {
+        while !text.is_char_boundary(byte) {
+            byte += 1;
+        }
+        byte
+    };
+
+    let step = size.saturating_sub(config.overlap).max(1);
+    let mut chunks = vec![];
+    let mut start = 0;
+    loop {
+        let end = (start + size).min(tokens.len());
+        let byte_start = to_char_boundary(offsets[start]);
+        let byte_end = to_char_boundary(offsets[end]);
+
+        if byte_end > byte_start {
+            let chunk = &text[byte_start..byte_end];
+            let char_start = text[..byte_start].chars().count();
+            chunks.push(Chunk {
+                text: chunk.to_string(),
+                tokens: num_tokens_from_str(chunk),
+                char_start,
+                char_end: char_start + chunk.chars().count(),
+            });
+        }
+
+        if end == tokens.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
 }
 
 // // Helper function to calculate length of essay
@@ -43,189 +196,285 @@ fn num_tokens_from_str(string: &str) -> usize {
 //     essay.split_whitespace().count()
 // }
 
-pub async fn process_dataframe(df: &Vec<ProcessedMessage>) -> Vec<SignalMessageWithEmbedding> {
+pub async fn process_dataframe(
+    df: &Vec<ProcessedMessage>,
+    embedder: &dyn Embedder,
+    chunking: &ChunkConfig,
+    policy: &StoragePolicy,
+) -> anyhow::Result<Vec<SignalMessageWithEmbedding>> {
     let mut new_list = Vec::new();
-    let ideal_token_size = 512;
-    let ideal_size = ideal_token_size * 3 / 4;
+    let mut embed = Vec::new();
 
     for data in df {
         let text = data.body.clone().unwrap_or(String::new());
-        let token_len = num_tokens_from_str(&text.clone());
+        let chunks = chunk_text(&text, chunking);
+        let chunk_count = chunks.len() as i32;
+        // Counted from sending rather than reading, which Signal does not sync reliably
+        let expires_at = match (data.sent_at, data.expire_timer) {
+            (Some(sent_at), Some(timer)) if timer > 0 => Some(sent_at as i64 + timer as i64 * 1000),
+            _ => None,
+        };
 
-        if token_len <= 512 {
+        for (j, chunk) in chunks.into_iter().enumerate() {
+            embed.push(policy.embeds(data.kind));
             new_list.push(SignalMessageWithEmbedding {
-                body: text.clone(),
-                tokens: token_len as i32,
-                embedding: get_embeddings_from_ollama(&text).await.unwrap(),
-                direction: data.direction.clone().unwrap().to_string(),
+                body: chunk.text,
+                direction: match data.direction.clone() {
+                    Some(x) => x.to_string(),
+                    None => String::new(),
+                },
                 contact: data.contact.clone(),
                 group_name: data.group.clone(),
                 attachments: data.attachments.clone(),
+                tokens: chunk.tokens as i32,
+                embedding: vec![],
+                embedding_model: None,
+                sent_at: data.sent_at.map(|x| x as i64),
+                sender_aci: data.sender.clone(),
+                thread_id: data.thread.clone(),
+                server_guid: data.server_guid.clone(),
+                kind: data.kind.as_str().to_string(),
+                chunk_index: j as i32,
+                chunk_count,
+                char_start: Some(chunk.char_start as i32),
+                char_end: Some(chunk.char_end as i32),
+                expires_at,
+                reply_to_sender_aci: data.quote_author_aci.clone(),
+                reply_to_sent_at: data.quote_sent_at.map(|x| x as i64),
+                // Only transcription sets a language
+                source: match data.language {
+                    Some(_) => String::from("transcript"),
+                    None => String::from("message"),
+                },
+                attachment: String::new(),
+                language: data.language.clone(),
             });
-        } else {
-            let words: Vec<String> = text
-                .clone()
-                .split_whitespace()
-                .map(|x| x.to_string())
-                .collect();
-            let total_words = words.len();
-            let chunks = (total_words + ideal_size - 1) / ideal_size;
-
-            for j in 0..chunks {
-                let start = j * ideal_size;
-                let end = (start + ideal_size).min(total_words);
-                let new_body: Vec<String> = words[start..end].to_vec();
-                let new_body_string = new_body.join(" ");
-                let new_body_token_len = num_tokens_from_str(&new_body_string);
-
-                let body = data.body.clone().unwrap_or(String::from("Error"));
-
-                let embedding = match get_embeddings_from_ollama(&body).await {
-                    Ok(x) => x,
-                    Err(err) => {
-                        panic!("err from ollama: {}", err);
-                    }
-                };
-                // println!("embedding: {:?}", embedding);
-
-                if new_body_token_len > 0 {
-                    new_list.push(SignalMessageWithEmbedding {
-                        body,
-                        direction: match data.direction.clone() {
-                            Some(x) => x.to_string(),
-                            None => String::new(),
-                        },
-                        contact: data.contact.clone(),
-                        group_name: data.group.clone(),
-                        attachments: data.attachments.clone(),
-                        tokens: token_len as i32,
-                        embedding,
-                    });
-                }
-            }
         }
     }
 
-    // println!("new_list: {:?}", new_list);
-    new_list
-}
-
-async fn get_embeddings_from_ollama(text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let url = "http://localhost:11434/api/embeddings";
-
-    let client = Client::new();
-
-    let payload = json!({
-        "model": "nomic-embed-text",
-        "prompt": text.replace("\n", " ")
-    });
-
-    let response = match client
-        .post(url)
-        .header("body-Type", "application/json")
-        .json(&payload)
-        .send()
-        .await {
-            Ok(x) => Some(x),
-            Err(err) => {
-                println!("err: {:?}", err);
-                None
-            },
-        };
-    
-    // println!("res: {:?}", response);
-
-    if response.is_some() {
-        let body: Value = response.unwrap().json().await?;
-        let embedding = body["embedding"]
-            .as_array()
-            .ok_or("Embedding not found in response")?
-            .iter()
-            .map(|v| v.as_f64().unwrap() as f32)
-            .collect();
-        // println!("embedding: {:?}", embedding);
-        Ok(embedding)
-    } else {
-        Err(format!("Error: {}", response.unwrap().text().await?).into())
+    // One request for the whole batch instead of one per row
+    let mut to_embed: Vec<&mut SignalMessageWithEmbedding> = new_list
+        .iter_mut()
+        .zip(embed)
+        .filter_map(|(x, embed)| embed.then_some(x))
+        .collect();
+    if to_embed.is_empty() {
+        return Ok(new_list);
+    }
+    let texts: Vec<String> = to_embed.iter().map(|x| x.body.clone()).collect();
+    let embeddings = embedder.embed_batch(&texts).await?;
+    if embeddings.len() != to_embed.len() {
+        anyhow::bail!(
+            "expected {} embeddings, got {}",
+            to_embed.len(),
+            embeddings.len()
+        );
+    }
+    for (item, embedding) in to_embed.iter_mut().zip(embeddings) {
+        item.embedding = embedding;
+        item.embedding_model = Some(embedder.model().to_string());
     }
+
+    Ok(new_list)
 }
 
 pub async fn process_message_to_get_embedding(
     data: Vec<ProcessedMessage>,
-) -> Vec<SignalMessageWithEmbedding> {
-    let mut new_list = process_dataframe(&data).await;
+    embedder: &dyn Embedder,
+    chunking: &ChunkConfig,
+    policy: &StoragePolicy,
+) -> anyhow::Result<Vec<SignalMessageWithEmbedding>> {
+    process_dataframe(&data, embedder, chunking, policy).await
+}
 
-    // println!("{:?}", new_list);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rag::embedder::FakeEmbedder;
+    use crate::signal::format_message::{Direction, MessageKind};
 
-    // Create embeddings
-    for item in &mut new_list {
-        let embedding = get_embeddings_from_ollama(&item.body).await.unwrap();
-        item.embedding = embedding;
+    fn config(size: usize, overlap: usize) -> ChunkConfig {
+        ChunkConfig { size, overlap }
     }
 
-    new_list
-}
+    /// The characters `chunk` claims to cover in `text`.
+    fn covered(text: &str, chunk: &Chunk) -> String {
+        text.chars()
+            .skip(chunk.char_start)
+            .take(chunk.char_end - chunk.char_start)
+            .collect()
+    }
 
-// // Create a new dataframe from the list
-// let mut df_new = DataFrame::new(vec![
-//     Series::new(
-//         "body".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.body.clone())
-//             .collect::<Vec<String>>(),
-//     ),
-//     Series::new(
-//         "direction".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.direction.clone())
-//             .collect::<Vec<String>>(),
-//     ),
-//     Series::new(
-//         "contact".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.contact.clone())
-//             .collect::<Vec<Option<String>>>(),
-//     ),
-//     Series::new(
-//         "sender".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.sender.clone())
-//             .collect::<Vec<Option<String>>>(),
-//     ),
-//     Series::new(
-//         "group".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.group_name.clone())
-//             .collect::<Vec<Option<String>>>(),
-//     ),
-//     Series::new(
-//         "attachments".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.attachments.clone().unwrap_or(vec![]).join(","))
-//             .collect::<Vec<String>>(),
-//     ),
-//     Series::new(
-//         "tokens".into(),
-//         new_list
-//             .iter()
-//             .map(|x| x.tokens as i32)
-//             .collect::<Vec<i32>>(),
-//     ),
-//     Series::new(
-//         "embedding".into(),
-//         new_list
-//             .iter()
-//             .map(|x| Series::new("".into(), x.embedding.clone()))
-//             .collect::<Vec<Series>>(),
-//     ),
-// ])
-// .unwrap();
-
-// // Print the first few rows of the dataframe
-// println!("{}", df_new.head(Some(5)));
+    fn message(kind: MessageKind, body: &str) -> ProcessedMessage {
+        ProcessedMessage {
+            kind,
+            direction: Some(Direction::From),
+            contact: Some(String::from("Alice")),
+            sender: Some(String::from("aci-alice")),
+            group: None,
+            body: Some(body.to_string()),
+            attachments: None,
+            sent_at: Some(1000),
+            thread: Some(String::from("aci-alice")),
+            server_guid: None,
+            target_sent_at: None,
+            target_author_aci: None,
+            emoji: None,
+            reaction_removed: false,
+            quote_author_aci: None,
+            quote_sent_at: None,
+            expire_timer: None,
+            voice_note: None,
+            language: None,
+        }
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert_eq!(chunk_text("", &ChunkConfig::default()), vec![]);
+        assert_eq!(num_tokens_from_str(""), 0);
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        let text = "see you at 5";
+        let chunks = chunk_text(text, &ChunkConfig::default());
+        assert_eq!(
+            chunks,
+            vec![Chunk {
+                text: text.to_string(),
+                tokens: num_tokens_from_str(text),
+                char_start: 0,
+                char_end: text.chars().count(),
+            }]
+        );
+    }
+
+    #[test]
+    fn long_text_is_split_at_the_size() {
+        let text = "word ".repeat(200);
+        let chunks = chunk_text(&text, &config(32, 0));
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].char_start, 0);
+        assert_eq!(chunks.last().unwrap().char_end, text.chars().count());
+        // Without overlap each chunk starts where the previous one ended
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[1].char_start, pair[0].char_end);
+        }
+        for chunk in &chunks {
+            assert!(chunk.tokens > 0 && chunk.tokens <= 32);
+            assert_eq!(covered(&text, chunk), chunk.text);
+        }
+        let joined: String = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(joined, text);
+    }
+
+    #[test]
+    fn chunks_overlap_by_the_configured_tokens() {
+        let text = "word ".repeat(200);
+        let chunks = chunk_text(&text, &config(32, 8));
+        for pair in chunks.windows(2) {
+            assert!(pair[1].char_start < pair[0].char_end);
+            let shared = covered(
+                &text,
+                &Chunk {
+                    char_start: pair[1].char_start,
+                    char_end: pair[0].char_end,
+                    ..pair[0].clone()
+                },
+            );
+            assert_eq!(num_tokens_from_str(&shared), 8);
+        }
+        assert_eq!(chunks.last().unwrap().char_end, text.chars().count());
+    }
+
+    #[test]
+    fn offsets_count_characters_in_multibyte_text() {
+        let text = "héllo wörld, 日本語のテキスト 🙂🙂 ".repeat(40);
+        let chunks = chunk_text(&text, &config(24, 6));
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].char_start, 0);
+        assert_eq!(chunks.last().unwrap().char_end, text.chars().count());
+        for chunk in &chunks {
+            assert!(!chunk.text.is_empty());
+            assert_eq!(
+                chunk.char_end - chunk.char_start,
+                chunk.text.chars().count()
+            );
+            assert_eq!(covered(&text, chunk), chunk.text);
+        }
+        for pair in chunks.windows(2) {
+            assert!(pair[1].char_start > pair[0].char_start);
+            assert!(pair[1].char_start <= pair[0].char_end);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_chunk_becomes_a_row() {
+        let text = "word ".repeat(200);
+        let mut long = message(MessageKind::Quote, &text);
+        long.expire_timer = Some(60);
+        long.quote_author_aci = Some(String::from("aci-bob"));
+        long.quote_sent_at = Some(900);
+        let messages = vec![long, message(MessageKind::Text, "see you at 5")];
+        let chunking = config(32, 0);
+
+        let rows = process_dataframe(
+            &messages,
+            &FakeEmbedder::new(16),
+            &chunking,
+            &StoragePolicy::default(),
+        )
+        .await
+        .unwrap();
+
+        let chunks = chunk_text(&text, &chunking);
+        assert_eq!(rows.len(), chunks.len() + 1);
+        for (index, (row, chunk)) in rows.iter().zip(&chunks).enumerate() {
+            assert_eq!(row.body, chunk.text);
+            assert_eq!(row.tokens as usize, chunk.tokens);
+            assert_eq!(row.chunk_index as usize, index);
+            assert_eq!(row.chunk_count as usize, chunks.len());
+            assert_eq!(row.char_start, Some(chunk.char_start as i32));
+            assert_eq!(row.char_end, Some(chunk.char_end as i32));
+            assert_eq!(row.kind, "quote");
+            assert_eq!(row.sent_at, Some(1000));
+            assert_eq!(row.sender_aci.as_deref(), Some("aci-alice"));
+            assert_eq!(row.expires_at, Some(61_000));
+            assert_eq!(row.reply_to_sender_aci.as_deref(), Some("aci-bob"));
+            assert_eq!(row.reply_to_sent_at, Some(900));
+            assert_eq!(row.source, "message");
+        }
+        let last = rows.last().unwrap();
+        assert_eq!((last.chunk_index, last.chunk_count), (0, 1));
+        assert_eq!(last.expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn the_policy_decides_what_is_embedded() {
+        let messages = vec![
+            message(MessageKind::Text, "see you at 5"),
+            message(MessageKind::Reaction, "👍"),
+        ];
+        let policy = StoragePolicy {
+            persist: vec![MessageKind::Text, MessageKind::Reaction],
+            embed: vec![MessageKind::Text],
+        };
+        let embedder = FakeEmbedder::new(16);
+
+        let rows = process_dataframe(&messages, &embedder, &ChunkConfig::default(), &policy)
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0].embedding,
+            embedder.embed("see you at 5").await.unwrap()
+        );
+        assert_eq!(rows[0].embedding_model.as_deref(), Some("fake"));
+        // Kept without a vector
+        assert_eq!(rows[1].kind, "reaction");
+        assert!(rows[1].embedding.is_empty());
+        assert_eq!(rows[1].embedding_model, None);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::bail;
+
+use crate::rag::dataframes::process_dataframe;
+use crate::rag::encryption::seal_rows;
+use crate::rag::sqlx::insert_embeddings;
+use crate::rag::vector_db::VectorDb;
+use crate::signal::process_incoming_message::ProcessedMessage;
+
+/// Replaces the stored text and embedding of the message `edit` targets, moving
+/// the previous revision to `message_revisions`.
+///
+/// Conversation windows quoting the message are dropped so `build-windows`
+/// renders them again with the new text.
+///
+/// An edit whose original was never stored becomes the message itself, so later
+/// edits still find it. Replayed or out-of-order edits older than the stored
+/// revision are ignored.
+pub async fn apply_edit(vector_db: &VectorDb, edit: ProcessedMessage) -> anyhow::Result<()> {
+    let (Some(sender), Some(target), Some(edited_at)) =
+        (edit.sender.clone(), edit.target_sent_at, edit.sent_at)
+    else {
+        bail!("edit without sender or target message");
+    };
+    let (target, edited_at) = (target as i64, edited_at as i64);
+
+    let current: Option<(i32, Option<i64>, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT revision, edited_at, kind FROM embeddings
+        WHERE sender_aci = $1 AND sent_at = $2 AND attachment = '' AND chunk_index = 0
+        "#,
+    )
+    .bind(&sender)
+    .bind(target)
+    .fetch_optional(&vector_db.pool)
+    .await?;
+    if let Some((_, Some(last_edited_at), _)) = current {
+        if last_edited_at >= edited_at {
+            return Ok(());
+        }
+    }
+
+    // Stored under the original's identity, so search keeps one row per message
+    let revised = ProcessedMessage {
+        sent_at: Some(target as u64),
+        ..edit
+    };
+    let mut rows = process_dataframe(
+        &vec![revised],
+        &*vector_db.embedder,
+        &vector_db.chunking,
+        &vector_db.policy,
+    )
+    .await?;
+    seal_rows(vector_db.cipher.as_ref(), &mut rows);
+
+    let mut tx = vector_db.pool.begin().await?;
+    let revision = match &current {
+        Some((revision, ..)) => {
+            sqlx::query(
+                r#"
+                INSERT INTO message_revisions
+                    (sender_aci, sent_at, revision, chunk_index, body, tokens, embedding, embedding_model, replaced_at)
+                SELECT sender_aci, sent_at, revision, chunk_index, body, tokens, embedding, embedding_model, $3
+                FROM embeddings
+                WHERE sender_aci = $1 AND sent_at = $2 AND attachment = ''
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(&sender)
+            .bind(target)
+            .bind(edited_at)
+            .execute(&mut *tx)
+            .await?;
+            // Their key does not change with the text, so `build-windows` would keep them
+            sqlx::query(
+                r#"
+                DELETE FROM conversation_windows WHERE id IN (
+                    SELECT m.window_id FROM conversation_window_messages m
+                    JOIN embeddings e ON e.id = m.embedding_id
+                    WHERE e.sender_aci = $1 AND e.sent_at = $2
+                )
+                "#,
+            )
+            .bind(&sender)
+            .bind(target)
+            .execute(&mut *tx)
+            .await?;
+            // Text extracted from attachments is not part of the edit
+            sqlx::query(
+                "DELETE FROM embeddings WHERE sender_aci = $1 AND sent_at = $2 AND attachment = ''",
+            )
+            .bind(&sender)
+            .bind(target)
+            .execute(&mut *tx)
+            .await?;
+            revision + 1
+        }
+        None => 1,
+    };
+
+    insert_embeddings(&mut tx, &rows).await?;
+    // An edited text is still a text, keep the kind of the original
+    sqlx::query(
+        r#"
+        UPDATE embeddings SET revision = $3, edited_at = $4, kind = COALESCE($5, kind)
+        WHERE sender_aci = $1 AND sent_at = $2
+        "#,
+    )
+    .bind(&sender)
+    .bind(target)
+    .bind(revision)
+    .bind(edited_at)
+    .bind(current.and_then(|(_, _, kind)| kind))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,373 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context as _};
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+/// Turns text into vectors. The same embedder must be used at ingest and at query time.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Model name, e.g. `nomic-embed-text`.
+    fn model(&self) -> &str;
+
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>>;
+
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for text in texts {
+            embeddings.push(self.embed(text).await?);
+        }
+        Ok(embeddings)
+    }
+
+    /// Vector dimension, discovered by embedding a probe string.
+    async fn dimension(&self) -> anyhow::Result<usize> {
+        Ok(self.embed("dimension probe").await?.len())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EmbedderBackend {
+    /// Ollama's native API.
+    Ollama,
+    /// Any server exposing `/v1/embeddings` (llama.cpp, vLLM, LocalAI, ...).
+    Openai,
+    /// Deterministic in-process embeddings, for tests.
+    Fake,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct EmbedderConfig {
+    #[clap(
+        long = "embedder",
+        env = "EMBEDDER",
+        value_enum,
+        default_value = "ollama",
+        help = "Embedding backend"
+    )]
+    pub backend: EmbedderBackend,
+    #[clap(
+        long = "embedding-url",
+        env = "EMBEDDING_URL",
+        help = "Base URL of the embedding server [default: http://localhost:11434 for ollama, http://localhost:8080 for openai]"
+    )]
+    pub url: Option<String>,
+    #[clap(
+        long = "embedding-model",
+        env = "EMBEDDING_MODEL",
+        default_value = "nomic-embed-text",
+        help = "Embedding model name"
+    )]
+    pub model: String,
+    #[clap(
+        long = "embedding-api-key",
+        env = "EMBEDDING_API_KEY",
+        help = "Bearer token for OpenAI-compatible servers"
+    )]
+    pub api_key: Option<String>,
+    #[clap(
+        long = "fake-embedding-dimension",
+        default_value_t = 768,
+        help = "Vector dimension of the fake backend"
+    )]
+    pub fake_dimension: usize,
+}
+
+impl Default for EmbedderConfig {
+    fn default() -> Self {
+        EmbedderConfig {
+            backend: EmbedderBackend::Ollama,
+            url: None,
+            model: String::from("nomic-embed-text"),
+            api_key: None,
+            fake_dimension: 768,
+        }
+    }
+}
+
+impl EmbedderConfig {
+    pub fn build(&self) -> Arc<dyn Embedder> {
+        match self.backend {
+            EmbedderBackend::Ollama => Arc::new(OllamaEmbedder::new(
+                self.url.as_deref().unwrap_or("http://localhost:11434"),
+                &self.model,
+            )),
+            EmbedderBackend::Openai => Arc::new(OpenAiEmbedder::new(
+                self.url.as_deref().unwrap_or("http://localhost:8080"),
+                &self.model,
+                self.api_key.clone(),
+            )),
+            EmbedderBackend::Fake => Arc::new(FakeEmbedder::new(self.fake_dimension)),
+        }
+    }
+}
+
+pub struct OllamaEmbedder {
+    client: Client,
+    host: String,
+    model: String,
+}
+
+impl OllamaEmbedder {
+    pub fn new(host: &str, model: &str) -> OllamaEmbedder {
+        OllamaEmbedder {
+            client: Client::new(),
+            host: host.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("no embedding in response"))
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let input: Vec<String> = texts.iter().map(|text| text.replace("\n", " ")).collect();
+        let payload = json!({
+            "model": self.model,
+            "input": input,
+        });
+
+        let body: Value = self
+            .client
+            .post(format!("{}/api/embed", self.host))
+            .json(&payload)
+            .send()
+            .await
+            .context("failed to reach ollama")?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        body["embeddings"]
+            .as_array()
+            .ok_or_else(|| anyhow!("embeddings not found in response"))?
+            .iter()
+            .map(parse_vector)
+            .collect()
+    }
+}
+
+pub struct OpenAiEmbedder {
+    client: Client,
+    base_url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(base_url: &str, model: &str, api_key: Option<String>) -> OpenAiEmbedder {
+        OpenAiEmbedder {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_batch(&[text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("no embedding in response"))
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> anyhow::Result<Vec<Vec<f32>>> {
+        let payload = json!({
+            "model": self.model,
+            "input": texts,
+        });
+
+        let mut request = self
+            .client
+            .post(format!("{}/v1/embeddings", self.base_url))
+            .json(&payload);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let body: Value = request
+            .send()
+            .await
+            .context("failed to reach embedding server")?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        parse_openai_embeddings(&body)
+    }
+}
+
+fn parse_openai_embeddings(body: &Value) -> anyhow::Result<Vec<Vec<f32>>> {
+    let data = body["data"]
+        .as_array()
+        .ok_or_else(|| anyhow!("data not found in response"))?;
+    // Entries carry their input position, which servers are not required to preserve.
+    let mut indexed = data
+        .iter()
+        .map(|item| Ok((item["index"].as_u64().unwrap_or(0), parse_vector(&item["embedding"])?)))
+        .collect::<anyhow::Result<Vec<(u64, Vec<f32>)>>>()?;
+    indexed.sort_by_key(|(index, _)| *index);
+
+    Ok(indexed.into_iter().map(|(_, embedding)| embedding).collect())
+}
+
+/// Hashes words into buckets, so equal texts get equal vectors and shared words raise similarity.
+pub struct FakeEmbedder {
+    dimension: usize,
+}
+
+impl FakeEmbedder {
+    pub fn new(dimension: usize) -> FakeEmbedder {
+        FakeEmbedder { dimension }
+    }
+}
+
+#[async_trait]
+impl Embedder for FakeEmbedder {
+    fn model(&self) -> &str {
+        "fake"
+    }
+
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let mut embedding = vec![0.0; self.dimension];
+        for word in text.split_whitespace() {
+            let bucket = fnv1a(word.to_lowercase().as_bytes()) % self.dimension as u64;
+            embedding[bucket as usize] += 1.0;
+        }
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|x| *x /= norm);
+        } else {
+            // pgvector cannot compute cosine distance for the zero vector.
+            embedding[0] = 1.0;
+        }
+        Ok(embedding)
+    }
+
+    async fn dimension(&self) -> anyhow::Result<usize> {
+        Ok(self.dimension)
+    }
+}
+
+// Stable across Rust versions, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn parse_vector(value: &Value) -> anyhow::Result<Vec<f32>> {
+    value
+        .as_array()
+        .ok_or_else(|| anyhow!("embedding not found in response"))?
+        .iter()
+        .map(|v| {
+            v.as_f64()
+                .map(|x| x as f32)
+                .ok_or_else(|| anyhow!("embedding contains a non-number"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn norm(embedding: &[f32]) -> f32 {
+        embedding.iter().map(|x| x * x).sum::<f32>().sqrt()
+    }
+
+    fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(x, y)| x * y).sum()
+    }
+
+    #[tokio::test]
+    async fn fake_embeddings_are_deterministic() {
+        let embedder = FakeEmbedder::new(64);
+        let first = embedder.embed("Dinner at Alice's").await.unwrap();
+        let again = FakeEmbedder::new(64)
+            .embed("dinner at alice's")
+            .await
+            .unwrap();
+        assert_eq!(first, again);
+        assert_eq!(
+            embedder
+                .embed_batch(&["Dinner at Alice's".to_string()])
+                .await
+                .unwrap(),
+            vec![first]
+        );
+    }
+
+    #[tokio::test]
+    async fn fake_embeddings_have_the_configured_dimension() {
+        let embedder = FakeEmbedder::new(32);
+        assert_eq!(embedder.dimension().await.unwrap(), 32);
+        assert_eq!(embedder.embed("hello there").await.unwrap().len(), 32);
+        assert_eq!(embedder.model(), "fake");
+    }
+
+    #[tokio::test]
+    async fn fake_embeddings_are_normalised() {
+        let embedder = FakeEmbedder::new(64);
+        for text in ["hello", "hello hello world", "a b c d e f g"] {
+            let embedding = embedder.embed(text).await.unwrap();
+            assert!((norm(&embedding) - 1.0).abs() < 1e-6, "{text}");
+        }
+        // Empty text still gets a unit vector rather than the zero vector
+        let empty = embedder.embed("").await.unwrap();
+        assert_eq!(norm(&empty), 1.0);
+    }
+
+    #[tokio::test]
+    async fn shared_words_raise_fake_similarity() {
+        let embedder = FakeEmbedder::new(256);
+        let query = embedder.embed("dinner on friday").await.unwrap();
+        let close = embedder.embed("friday dinner plans").await.unwrap();
+        let far = embedder.embed("the train is late").await.unwrap();
+        assert!(dot(&query, &close) > dot(&query, &far));
+    }
+
+    #[test]
+    fn openai_embeddings_follow_their_index() {
+        let body = json!({
+            "data": [
+                {"index": 2, "embedding": [2.0, 2.5]},
+                {"index": 0, "embedding": [0.0, 0.5]},
+                {"index": 1, "embedding": [1.0, 1.5]},
+            ]
+        });
+        assert_eq!(
+            parse_openai_embeddings(&body).unwrap(),
+            vec![vec![0.0, 0.5], vec![1.0, 1.5], vec![2.0, 2.5]]
+        );
+    }
+
+    #[test]
+    fn malformed_openai_responses_are_errors() {
+        assert!(parse_openai_embeddings(&json!({"error": "overloaded"})).is_err());
+        let body = json!({"data": [{"index": 0, "embedding": [0.5, "x"]}]});
+        assert!(parse_openai_embeddings(&body).is_err());
+    }
+}
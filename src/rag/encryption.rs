@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context as _};
+use argon2::Argon2;
+use base64::prelude::*;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use clap::Args;
+use sqlx::{Pool, Postgres};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::rag::attachment_store::replace_file;
+use crate::rag::dataframes::SignalMessageWithEmbedding;
+use crate::rag::vector_db::VectorDb;
+
+// Marks sealed files; anything else is read as stored before encryption was turned on
+const FILE_MAGIC: &[u8] = b"SVDB\x01";
+// Marks sealed `body` values, which are text columns, so the sealed bytes are base64
+const TEXT_PREFIX: &str = "svdb:1:";
+// Sealed with the derived key when encryption is set up, to recognise a wrong one
+const VERIFIER: &[u8] = b"signal-vector-db";
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+/// Every table with a `body`, sealed by [`encrypt_existing`].
+const TABLES: [&str; 3] = ["embeddings", "message_revisions", "conversation_windows"];
+// Rows sealed per transaction
+const BATCH_SIZE: i64 = 500;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct EncryptionConfig {
+    #[clap(
+        long = "encrypt-at-rest",
+        help = "Encrypt attachment files and message bodies with a key derived from the passphrase"
+    )]
+    pub encrypt_at_rest: bool,
+    #[clap(
+        long = "encryption-key-file",
+        env = "ENCRYPTION_KEY_FILE",
+        help = "Derive the encryption key from this file instead of the passphrase"
+    )]
+    pub key_file: Option<PathBuf>,
+}
+
+/// Seals and opens stored attachments and message bodies. Embeddings are computed from
+/// the plaintext before sealing, so similarity search works unchanged.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn derive(secret: &[u8], salt: &[u8]) -> anyhow::Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(secret, salt, &mut key)
+            .map_err(|error| anyhow!("failed to derive the encryption key: {error}"))?;
+        Ok(Cipher {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, plaintext)
+            .expect("encryption of an in-memory buffer does not fail");
+        [FILE_MAGIC, nonce.as_slice(), &ciphertext].concat()
+    }
+
+    /// Decrypts what [`Cipher::seal`] produced; data without its marker is returned as is.
+    pub fn open(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(sealed) = data.strip_prefix(FILE_MAGIC) else {
+            return Ok(data.to_vec());
+        };
+        if sealed.len() < NONCE_LEN {
+            bail!("sealed data is truncated");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("sealed data does not match the key"))
+    }
+
+    /// Whether this is the key `verifier` was sealed with when encryption was set up.
+    fn verifies(&self, verifier: &[u8]) -> bool {
+        matches!(self.open(verifier), Ok(opened) if opened == VERIFIER)
+    }
+
+    pub fn seal_text(&self, text: &str) -> String {
+        format!(
+            "{TEXT_PREFIX}{}",
+            BASE64_STANDARD.encode(self.seal(text.as_bytes()))
+        )
+    }
+
+    /// Decrypts a sealed `body`; plaintext stored before encryption was turned on passes
+    /// through, and a value that cannot be opened is kept with a warning.
+    pub fn open_text(&self, text: &str) -> String {
+        let Some(encoded) = text.strip_prefix(TEXT_PREFIX) else {
+            return text.to_string();
+        };
+        let opened = BASE64_STANDARD
+            .decode(encoded)
+            .map_err(anyhow::Error::from)
+            .and_then(|sealed| self.open(&sealed))
+            .and_then(|plaintext| Ok(String::from_utf8(plaintext)?));
+        match opened {
+            Ok(text) => text,
+            Err(error) => {
+                warn!(%error, "failed to decrypt stored text");
+                text.to_string()
+            }
+        }
+    }
+}
+
+/// Seals the bodies of `rows` about to be inserted.
+pub fn seal_rows(cipher: Option<&Cipher>, rows: &mut [SignalMessageWithEmbedding]) {
+    if let Some(cipher) = cipher {
+        for row in rows {
+            row.body = cipher.seal_text(&row.body);
+        }
+    }
+}
+
+/// Whether the file at `path` was written by [`Cipher::seal`].
+pub async fn file_is_sealed(path: &Path) -> std::io::Result<bool> {
+    let mut prefix = Vec::with_capacity(FILE_MAGIC.len());
+    tokio::fs::File::open(path)
+        .await?
+        .take(FILE_MAGIC.len() as u64)
+        .read_to_end(&mut prefix)
+        .await?;
+    Ok(prefix == FILE_MAGIC)
+}
+
+/// Opens a `body` read from the database.
+pub fn open_body(cipher: Option<&Cipher>, body: Option<String>) -> Option<String> {
+    match (cipher, body) {
+        (Some(cipher), Some(body)) => Some(cipher.open_text(&body)),
+        (_, body) => body,
+    }
+}
+
+/// Returns the cipher for this database, setting encryption up on the first run with
+/// `--encrypt-at-rest`. Once set up the key is always required, so later runs cannot
+/// store plaintext next to sealed data or show sealed text.
+pub async fn load_cipher(
+    pool: &Pool<Postgres>,
+    config: &EncryptionConfig,
+    passphrase: Option<&str>,
+) -> anyhow::Result<Option<Cipher>> {
+    let stored: Option<(Vec<u8>, Vec<u8>)> =
+        sqlx::query_as("SELECT salt, verifier FROM encryption")
+            .fetch_optional(pool)
+            .await?;
+    if stored.is_none() && !config.encrypt_at_rest {
+        return Ok(None);
+    }
+
+    let secret = match (&config.key_file, passphrase) {
+        (Some(key_file), _) => tokio::fs::read(key_file)
+            .await
+            .with_context(|| format!("failed to read {}", key_file.display()))?,
+        (None, Some(passphrase)) => passphrase.as_bytes().to_vec(),
+        (None, None) => match stored {
+            Some(_) => {
+                bail!("stored data is encrypted; pass --passphrase or --encryption-key-file")
+            }
+            None => bail!("--encrypt-at-rest needs --passphrase or --encryption-key-file"),
+        },
+    };
+
+    let (salt, verifier) = match stored {
+        Some(stored) => stored,
+        None => {
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = Cipher::derive(&secret, &salt)?;
+            // A concurrent first run may win; its salt is read back below
+            sqlx::query(
+                "INSERT INTO encryption (salt, verifier) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            )
+            .bind(&salt[..])
+            .bind(cipher.seal(VERIFIER))
+            .execute(pool)
+            .await?;
+            info!("encryption at rest set up; run encrypt-existing to seal what is stored");
+            sqlx::query_as("SELECT salt, verifier FROM encryption")
+                .fetch_one(pool)
+                .await?
+        }
+    };
+
+    let cipher = Cipher::derive(&secret, &salt)?;
+    if !cipher.verifies(&verifier) {
+        bail!("wrong passphrase or key file for the encrypted data");
+    }
+    Ok(Some(cipher))
+}
+
+#[derive(Debug, Default)]
+pub struct EncryptStats {
+    /// Stored texts sealed.
+    pub rows: u64,
+    /// Attachment files sealed.
+    pub files: usize,
+}
+
+/// Seals what was stored in plaintext before encryption was set up: every `body`, and
+/// every file under the attachments root. Sealed data is left alone, so it can be run
+/// again, for example after an interruption.
+pub async fn encrypt_existing(vector_db: &VectorDb) -> anyhow::Result<EncryptStats> {
+    let Some(cipher) = &vector_db.cipher else {
+        bail!("encryption at rest is not set up; pass --encrypt-at-rest");
+    };
+    let mut stats = EncryptStats::default();
+
+    for table in TABLES {
+        loop {
+            let mut tx = vector_db.pool.begin().await?;
+            let rows: Vec<(i64, String)> = sqlx::query_as(&format!(
+                "SELECT id, body FROM {table} WHERE body IS NOT NULL AND body NOT LIKE $1 \
+                    ORDER BY id LIMIT $2 FOR UPDATE"
+            ))
+            .bind(format!("{TEXT_PREFIX}%"))
+            .bind(BATCH_SIZE)
+            .fetch_all(&mut *tx)
+            .await?;
+            if rows.is_empty() {
+                break;
+            }
+            for (id, body) in &rows {
+                sqlx::query(&format!("UPDATE {table} SET body = $1 WHERE id = $2"))
+                    .bind(cipher.seal_text(body))
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            stats.rows += rows.len() as u64;
+            info!(table, rows = stats.rows, "sealed stored text");
+        }
+    }
+
+    // Walked rather than read from `attachments`, which misses files saved before it
+    let mut dirs = vec![vector_db.attachments.dir.clone()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read {}", dir.display()))
+            }
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file()
+                && path
+                    .extension()
+                    .is_none_or(|extension| extension != "partial")
+                && !file_is_sealed(&path).await?
+            {
+                let data = tokio::fs::read(&path).await?;
+                replace_file(&path, &cipher.seal(&data)).await?;
+                stats.files += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: &[u8] = b"0123456789abcdef";
+
+    fn cipher(secret: &str) -> Cipher {
+        Cipher::derive(secret.as_bytes(), SALT).unwrap()
+    }
+
+    #[test]
+    fn sealed_data_opens_to_the_plaintext() {
+        let cipher = cipher("correct horse");
+        let sealed = cipher.seal(b"meet at noon");
+        assert!(sealed.starts_with(FILE_MAGIC));
+        assert!(!sealed.windows(12).any(|window| window == b"meet at noon"));
+        assert_eq!(cipher.open(&sealed).unwrap(), b"meet at noon");
+    }
+
+    #[test]
+    fn sealed_text_opens_to_the_plaintext() {
+        let cipher = cipher("correct horse");
+        let sealed = cipher.seal_text("meet at noon");
+        assert!(sealed.starts_with(TEXT_PREFIX));
+        assert_eq!(cipher.open_text(&sealed), "meet at noon");
+    }
+
+    #[test]
+    fn another_key_is_rejected_by_the_verifier() {
+        let verifier = cipher("correct horse").seal(VERIFIER);
+        assert!(cipher("correct horse").verifies(&verifier));
+        assert!(!cipher("battery staple").verifies(&verifier));
+        assert!(cipher("battery staple").open(&verifier).is_err());
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let cipher = cipher("correct horse");
+        assert!(cipher.open(&[FILE_MAGIC, b"short"].concat()).is_err());
+    }
+
+    #[test]
+    fn plaintext_stored_before_encryption_passes_through() {
+        let cipher = cipher("correct horse");
+        assert_eq!(cipher.open(b"plain file").unwrap(), b"plain file");
+        assert_eq!(cipher.open_text("plain body"), "plain body");
+        assert_eq!(
+            open_body(Some(&cipher), Some(String::from("plain body"))),
+            Some(String::from("plain body"))
+        );
+    }
+
+    #[test]
+    fn bodies_are_left_alone_without_a_cipher() {
+        let sealed = cipher("correct horse").seal_text("meet at noon");
+        assert_eq!(open_body(None, Some(sealed.clone())), Some(sealed));
+        assert_eq!(open_body(None, None), None);
+        assert_eq!(open_body(Some(&cipher("correct horse")), None), None);
+    }
+}
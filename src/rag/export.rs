@@ -0,0 +1,601 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use chrono::{DateTime, Utc};
+use clap::{Args, ValueEnum};
+use futures::TryStreamExt;
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Postgres, QueryBuilder};
+
+use crate::rag::attachments::describe_source;
+use crate::rag::encryption::open_body;
+use crate::rag::parquet::{read_parquet, ParquetExport};
+use crate::rag::retention::apply_thread_timers;
+use crate::rag::search::{join_chunks, parse_datetime, search_messages, SearchFilter};
+use crate::rag::sqlx::{ensure_active_model, message_deleted};
+use crate::rag::vector_db::VectorDb;
+use crate::rag::windows::speaker;
+use crate::signal::format_message::MessageKind;
+
+// Rows embedded per request, and per Parquet row group
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON object per stored row.
+    Jsonl,
+    Csv,
+    /// A readable transcript per conversation; cannot be imported.
+    Markdown,
+    Parquet,
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct ExportFilter {
+    #[clap(
+        long,
+        short = 'c',
+        help = "Only messages whose contact contains this text"
+    )]
+    pub contact: Option<String>,
+    #[clap(
+        long,
+        short = 'g',
+        help = "Only messages whose group name contains this text"
+    )]
+    pub group: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_datetime,
+        help = "Only messages from this date (YYYY-MM-DD or RFC 3339)"
+    )]
+    pub since: Option<DateTime<Utc>>,
+    #[clap(
+        long,
+        value_parser = parse_datetime,
+        help = "Only messages before this date (YYYY-MM-DD or RFC 3339)"
+    )]
+    pub until: Option<DateTime<Utc>>,
+    #[clap(
+        long,
+        short = 't',
+        help = "Only this conversation: contact uuid or group master key (hex string)"
+    )]
+    pub thread: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub filter: ExportFilter,
+    /// Adds the vector of every row; large, and only meaningful with the same model.
+    pub embeddings: bool,
+    /// Exports the rows matching this search, best first, instead of every message.
+    pub query: Option<String>,
+    /// Maximum number of search results.
+    pub limit: i64,
+}
+
+/// One row of `embeddings` as exported: a message, or one chunk of a long one.
+#[derive(Clone, Debug, Default, FromRow, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    pub thread_id: Option<String>,
+    pub sender_aci: Option<String>,
+    pub sent_at: Option<i64>,
+    pub kind: Option<String>,
+    pub direction: Option<String>,
+    pub contact: Option<String>,
+    pub group_name: Option<String>,
+    pub body: Option<String>,
+    pub attachments: Option<String>,
+    /// `message`, or how the text was obtained from `attachment`.
+    pub source: Option<String>,
+    pub attachment: Option<String>,
+    /// Detected in a transcribed voice note.
+    pub language: Option<String>,
+    pub tokens: Option<i32>,
+    #[serde(default)]
+    pub chunk_index: i32,
+    #[serde(default = "one")]
+    pub chunk_count: i32,
+    pub char_start: Option<i32>,
+    pub char_end: Option<i32>,
+    #[serde(default)]
+    pub revision: i32,
+    pub edited_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub reply_to_sender_aci: Option<String>,
+    pub reply_to_sent_at: Option<i64>,
+    pub embedding_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+}
+
+fn one() -> i32 {
+    1
+}
+
+/// Column order of CSV and Parquet exports.
+pub const EXPORT_COLUMNS: [&str; 24] = [
+    "thread_id",
+    "sender_aci",
+    "sent_at",
+    "kind",
+    "direction",
+    "contact",
+    "group_name",
+    "body",
+    "attachments",
+    "source",
+    "attachment",
+    "language",
+    "tokens",
+    "chunk_index",
+    "chunk_count",
+    "char_start",
+    "char_end",
+    "revision",
+    "edited_at",
+    "expires_at",
+    "reply_to_sender_aci",
+    "reply_to_sent_at",
+    "embedding_model",
+    "embedding",
+];
+
+#[derive(Debug, Default)]
+pub struct ImportedRows {
+    pub read: usize,
+    pub inserted: u64,
+    /// Rows embedded again because they came without a vector of the active model.
+    pub embedded: usize,
+}
+
+/// Writes the stored rows matching `options` to `output`, returning how many.
+pub async fn export_messages(
+    vector_db: &VectorDb,
+    options: &ExportOptions,
+    output: &Path,
+) -> anyhow::Result<usize> {
+    let ids = match &options.query {
+        Some(query) => {
+            let filter = SearchFilter {
+                limit: options.limit,
+                contact: options.filter.contact.clone(),
+                group: options.filter.group.clone(),
+                since: options.filter.since,
+                until: options.filter.until,
+                thread: options.filter.thread.clone(),
+                ..Default::default()
+            };
+            let results = search_messages(vector_db, query, &filter).await?;
+            Some(results.iter().map(|result| result.id).collect::<Vec<i64>>())
+        }
+        None => None,
+    };
+
+    // Search results keep their rank; everything else reads as conversations
+    let mut rows = sqlx::query_as::<_, ExportedMessage>(
+        r#"
+        SELECT thread_id, sender_aci, sent_at, kind, direction, contact, group_name, body,
+            attachments::text AS attachments, source, NULLIF(attachment, '') AS attachment,
+            language, tokens, chunk_index, chunk_count, char_start, char_end, revision, edited_at,
+            expires_at, reply_to_sender_aci, reply_to_sent_at, embedding_model,
+            CASE WHEN $6 THEN embedding::real[] END AS embedding
+        FROM embeddings
+        WHERE ($1::text IS NULL OR strpos(lower(contact), lower($1)) > 0)
+            AND ($2::text IS NULL OR strpos(lower(group_name), lower($2)) > 0)
+            AND ($3::timestamptz IS NULL
+                OR COALESCE(to_timestamp(sent_at / 1000.0), created_at) >= $3)
+            AND ($4::timestamptz IS NULL
+                OR COALESCE(to_timestamp(sent_at / 1000.0), created_at) < $4)
+            AND ($5::text IS NULL OR thread_id = $5)
+            AND ($7::bigint[] IS NULL OR id = ANY($7))
+        ORDER BY array_position($7, id), thread_id, sent_at, attachment, source, chunk_index, id
+        "#,
+    )
+    .bind(&options.filter.contact)
+    .bind(&options.filter.group)
+    .bind(options.filter.since)
+    .bind(options.filter.until)
+    .bind(&options.filter.thread)
+    .bind(options.embeddings)
+    .bind(&ids)
+    .fetch(&vector_db.pool)
+    // Exports are plaintext, like the messages they came from
+    .map_ok(|row| ExportedMessage {
+        body: open_body(vector_db.cipher.as_ref(), row.body.clone()),
+        ..row
+    });
+
+    let file =
+        File::create(output).with_context(|| format!("failed to create {}", output.display()))?;
+    let mut count = 0;
+    match options.format {
+        ExportFormat::Jsonl => {
+            let mut writer = BufWriter::new(file);
+            while let Some(row) = rows.try_next().await? {
+                serde_json::to_writer(&mut writer, &row)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Csv => {
+            let mut writer = BufWriter::new(file);
+            writeln!(writer, "{}", EXPORT_COLUMNS.join(","))?;
+            while let Some(row) = rows.try_next().await? {
+                writeln!(writer, "{}", csv_record(&row).join(","))?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Markdown => {
+            let mut transcript = MarkdownTranscript::new(BufWriter::new(file));
+            while let Some(row) = rows.try_next().await? {
+                transcript.push(row)?;
+                count += 1;
+            }
+            transcript.finish()?;
+        }
+        ExportFormat::Parquet => {
+            let mut writer = ParquetExport::new(file)?;
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            while let Some(row) = rows.try_next().await? {
+                batch.push(row);
+                count += 1;
+                if batch.len() == BATCH_SIZE {
+                    writer.write(&batch)?;
+                    batch.clear();
+                }
+            }
+            writer.write(&batch)?;
+            writer.close()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Reads rows written by [`export_messages`] as JSONL or Parquet and stores those not yet
+/// present, embedding rows whose vector is missing or from another model.
+pub async fn import_messages(
+    vector_db: &VectorDb,
+    format: ExportFormat,
+    path: &Path,
+) -> anyhow::Result<ImportedRows> {
+    let batches: Box<dyn Iterator<Item = anyhow::Result<Vec<ExportedMessage>>> + Send> =
+        match format {
+            ExportFormat::Jsonl => Box::new(jsonl_batches(File::open(path)?)),
+            ExportFormat::Parquet => Box::new(read_parquet(File::open(path)?, BATCH_SIZE)?),
+            ExportFormat::Csv | ExportFormat::Markdown => {
+                bail!("only jsonl and parquet exports can be imported")
+            }
+        };
+
+    let mut stats = ImportedRows::default();
+    for batch in batches {
+        let mut batch = batch?;
+        stats.read += batch.len();
+        if !vector_db.retention.legal_hold {
+            let mut kept = Vec::with_capacity(batch.len());
+            for row in batch {
+                if let (Some(sender), Some(sent_at)) = (&row.sender_aci, row.sent_at) {
+                    if message_deleted(&vector_db.pool, sender, sent_at).await? {
+                        continue;
+                    }
+                }
+                kept.push(row);
+            }
+            batch = kept;
+        }
+        stats.embedded += embed_stale(vector_db, &mut batch).await?;
+        stats.inserted += insert_exported(vector_db, &batch).await?;
+    }
+    Ok(stats)
+}
+
+/// Reads a JSONL export in batches of [`BATCH_SIZE`] rows, skipping blank lines.
+fn jsonl_batches(file: File) -> impl Iterator<Item = anyhow::Result<Vec<ExportedMessage>>> {
+    let mut lines = BufReader::new(file).lines().enumerate();
+    std::iter::from_fn(move || {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        for (number, line) in lines.by_ref() {
+            let row = line.map_err(anyhow::Error::from).and_then(|line| {
+                if line.trim().is_empty() {
+                    return Ok(None);
+                }
+                serde_json::from_str(&line)
+                    .map(Some)
+                    .with_context(|| format!("line {}", number + 1))
+            });
+            match row {
+                Ok(Some(row)) => batch.push(row),
+                Ok(None) => continue,
+                Err(error) => return Some(Err(error)),
+            }
+            if batch.len() == BATCH_SIZE {
+                break;
+            }
+        }
+        (!batch.is_empty()).then_some(Ok(batch))
+    })
+}
+
+/// Replaces vectors that are missing or not from the active model, following the
+/// storage policy for which kinds get one.
+async fn embed_stale(vector_db: &VectorDb, rows: &mut [ExportedMessage]) -> anyhow::Result<usize> {
+    let model = vector_db.embedder.model();
+    let mut stale: Vec<&mut ExportedMessage> = vec![];
+    for row in rows.iter_mut() {
+        let current = row.embedding.is_some() && row.embedding_model.as_deref() == Some(model);
+        if current {
+            continue;
+        }
+        row.embedding = None;
+        row.embedding_model = None;
+        let kind = row
+            .kind
+            .as_deref()
+            .and_then(|kind| MessageKind::from_str(kind, true).ok())
+            .unwrap_or(MessageKind::Text);
+        if row.body.is_some() && vector_db.policy.embeds(kind) {
+            stale.push(row);
+        }
+    }
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let texts: Vec<String> = stale
+        .iter()
+        .map(|row| row.body.clone().unwrap_or_default())
+        .collect();
+    let embeddings = vector_db.embedder.embed_batch(&texts).await?;
+    if embeddings.len() != stale.len() {
+        bail!(
+            "expected {} embeddings, got {}",
+            stale.len(),
+            embeddings.len()
+        );
+    }
+    for (row, embedding) in stale.iter_mut().zip(embeddings) {
+        row.embedding = Some(embedding);
+        row.embedding_model = Some(model.to_string());
+    }
+    Ok(texts.len())
+}
+
+async fn insert_exported(vector_db: &VectorDb, rows: &[ExportedMessage]) -> anyhow::Result<u64> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let mut query = QueryBuilder::<Postgres>::new(
+        "INSERT INTO embeddings (thread_id,sender_aci,sent_at,kind,direction,contact,group_name,\
+            body,attachments,source,attachment,language,tokens,chunk_index,chunk_count,char_start,\
+            char_end,revision,edited_at,expires_at,reply_to_sender_aci,reply_to_sent_at,\
+            embedding_model,embedding) ",
+    );
+    query.push_values(rows, |mut row, msg| {
+        row.push_bind(&msg.thread_id)
+            .push_bind(&msg.sender_aci)
+            .push_bind(msg.sent_at)
+            .push_bind(&msg.kind)
+            .push_bind(&msg.direction)
+            .push_bind(&msg.contact)
+            .push_bind(&msg.group_name)
+            .push_bind(match &vector_db.cipher {
+                Some(cipher) => msg.body.as_deref().map(|body| cipher.seal_text(body)),
+                None => msg.body.clone(),
+            })
+            .push_bind(&msg.attachments)
+            .push_bind(msg.source.as_deref().unwrap_or("message"))
+            .push_bind(msg.attachment.as_deref().unwrap_or_default())
+            .push_bind(&msg.language)
+            .push_bind(msg.tokens)
+            .push_bind(msg.chunk_index)
+            .push_bind(msg.chunk_count)
+            .push_bind(msg.char_start)
+            .push_bind(msg.char_end)
+            .push_bind(msg.revision)
+            .push_bind(msg.edited_at)
+            .push_bind(msg.expires_at)
+            .push_bind(&msg.reply_to_sender_aci)
+            .push_bind(msg.reply_to_sent_at)
+            .push_bind(&msg.embedding_model)
+            .push_bind(msg.embedding.clone().map(Vector::from));
+    });
+    query.push(" ON CONFLICT (sender_aci, sent_at, attachment, source, chunk_index) DO NOTHING");
+    let mut tx = vector_db.pool.begin().await?;
+    let inserted = query.build().execute(&mut *tx).await?.rows_affected();
+    let (sender_acis, sent_ats): (Vec<String>, Vec<i64>) = rows
+        .iter()
+        .filter(|row| row.expires_at.is_none())
+        .filter_map(|row| Some((row.sender_aci.clone()?, row.sent_at?)))
+        .unzip();
+    apply_thread_timers(&mut tx, &sender_acis, &sent_ats).await?;
+    // Stale rows were embedded again with the active model just before
+    ensure_active_model(&mut tx, vector_db.embedder.model()).await?;
+    tx.commit().await?;
+    Ok(inserted)
+}
+
+fn csv_record(row: &ExportedMessage) -> Vec<String> {
+    let text = |value: &Option<String>| csv_field(value.as_deref().unwrap_or_default());
+    let number = |value: Option<i64>| value.map(|x| x.to_string()).unwrap_or_default();
+    vec![
+        text(&row.thread_id),
+        text(&row.sender_aci),
+        number(row.sent_at),
+        text(&row.kind),
+        text(&row.direction),
+        text(&row.contact),
+        text(&row.group_name),
+        text(&row.body),
+        text(&row.attachments),
+        text(&row.source),
+        text(&row.attachment),
+        text(&row.language),
+        number(row.tokens.map(i64::from)),
+        row.chunk_index.to_string(),
+        row.chunk_count.to_string(),
+        number(row.char_start.map(i64::from)),
+        number(row.char_end.map(i64::from)),
+        row.revision.to_string(),
+        number(row.edited_at),
+        number(row.expires_at),
+        text(&row.reply_to_sender_aci),
+        number(row.reply_to_sent_at),
+        text(&row.embedding_model),
+        // As a JSON array, which spreadsheet and dataframe tools can parse back
+        match &row.embedding {
+            Some(embedding) => csv_field(&serde_json::to_string(embedding).unwrap_or_default()),
+            None => String::new(),
+        },
+    ]
+}
+
+/// Quotes a field when it holds a separator, quote or line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes rows as one section per conversation, joining the chunks of long messages.
+struct MarkdownTranscript<W: Write> {
+    writer: W,
+    thread: Option<String>,
+    /// Chunks of the message being written, until a row of another message arrives.
+    message: Vec<ExportedMessage>,
+}
+
+impl<W: Write> MarkdownTranscript<W> {
+    fn new(writer: W) -> Self {
+        MarkdownTranscript {
+            writer,
+            thread: None,
+            message: vec![],
+        }
+    }
+
+    fn push(&mut self, row: ExportedMessage) -> std::io::Result<()> {
+        let same_message = self.message.first().is_some_and(|first| {
+            first.sender_aci.is_some()
+                && first.sent_at.is_some()
+                && first.sender_aci == row.sender_aci
+                && first.sent_at == row.sent_at
+                && first.attachment == row.attachment
+                && first.source == row.source
+        });
+        if !same_message {
+            self.write_message()?;
+        }
+        self.message.push(row);
+        Ok(())
+    }
+
+    fn write_message(&mut self) -> std::io::Result<()> {
+        let Some(first) = self.message.first() else {
+            return Ok(());
+        };
+
+        if self.thread.is_none() || self.thread != first.thread_id {
+            let title = match (&first.group_name, &first.contact) {
+                (Some(group), _) => group.clone(),
+                (None, contact) => speaker(None, contact.as_deref()),
+            };
+            if self.thread.is_some() {
+                writeln!(self.writer)?;
+            }
+            writeln!(self.writer, "## {title}\n")?;
+            self.thread = first.thread_id.clone();
+        }
+
+        let time = first
+            .sent_at
+            .and_then(DateTime::from_timestamp_millis)
+            .map(|sent_at| sent_at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let who = speaker(first.direction.as_deref(), first.contact.as_deref());
+        let body = join_chunks(
+            self.message
+                .iter()
+                .map(|chunk| (chunk.body.clone(), chunk.char_start)),
+        );
+        let from = match &first.attachment {
+            Some(attachment) => format!(
+                ", {}",
+                describe_source(first.source.as_deref().unwrap_or_default(), attachment)
+            ),
+            None => String::new(),
+        };
+        // Keeps multi-line messages inside their list item
+        writeln!(
+            self.writer,
+            "- **{who}** ({time}{from}): {}",
+            body.replace('\n', "\n  ")
+        )?;
+
+        self.message.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.write_message()?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_are_quoted_when_needed() {
+        let cases = [
+            ("", ""),
+            ("plain text", "plain text"),
+            ("one, two", "\"one, two\""),
+            ("say \"hi\"", "\"say \"\"hi\"\"\""),
+            ("line\nbreak", "\"line\nbreak\""),
+            ("carriage\rreturn", "\"carriage\rreturn\""),
+        ];
+        for (value, field) in cases {
+            assert_eq!(csv_field(value), field, "{value}");
+        }
+    }
+
+    #[test]
+    fn records_follow_the_export_columns() {
+        let row = ExportedMessage {
+            thread_id: Some(String::from("aci-alice")),
+            sent_at: Some(1000),
+            kind: Some(String::from("text")),
+            body: Some(String::from("milk, eggs and \"bread\"")),
+            tokens: Some(7),
+            chunk_count: 1,
+            embedding: Some(vec![0.5, -1.0]),
+            ..ExportedMessage::default()
+        };
+        let record = csv_record(&row);
+        assert_eq!(record.len(), EXPORT_COLUMNS.len());
+        let field = |name: &str| {
+            let index = EXPORT_COLUMNS.iter().position(|column| *column == name);
+            record[index.unwrap()].as_str()
+        };
+        assert_eq!(field("thread_id"), "aci-alice");
+        assert_eq!(field("sender_aci"), "");
+        assert_eq!(field("sent_at"), "1000");
+        assert_eq!(field("body"), "\"milk, eggs and \"\"bread\"\"\"");
+        assert_eq!(field("tokens"), "7");
+        assert_eq!(field("chunk_index"), "0");
+        assert_eq!(field("chunk_count"), "1");
+        assert_eq!(field("char_start"), "");
+        assert_eq!(field("embedding"), "\"[0.5,-1.0]\"");
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Context as _};
+use base64::prelude::*;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+/// Where Ollama listens unless `--ollama-url` says otherwise.
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Sends an already templated prompt to the Ollama at `url` and returns the completion.
+pub async fn generate_from_ollama(url: &str, model: &str, prompt: &str) -> anyhow::Result<String> {
+    // The prompt is formatted with `prompt_template`, so Ollama must not apply its own.
+    let payload = json!({
+        "model": model,
+        "prompt": prompt,
+        "raw": true,
+        "stream": false,
+    });
+    ollama_generate(url, &payload).await
+}
+
+/// Asks an Ollama model with vision, e.g. `llava`, about an image.
+pub async fn describe_image_with_ollama(
+    url: &str,
+    model: &str,
+    prompt: &str,
+    image: &[u8],
+) -> anyhow::Result<String> {
+    // Unlike text prompts, the model's own template is needed to place the image
+    let payload = json!({
+        "model": model,
+        "prompt": prompt,
+        "images": [BASE64_STANDARD.encode(image)],
+        "stream": false,
+    });
+    ollama_generate(url, &payload).await
+}
+
+/// Posts `payload` to Ollama's `/api/generate` under `url` and returns the response text.
+async fn ollama_generate(url: &str, payload: &Value) -> anyhow::Result<String> {
+    let url = format!("{}/api/generate", url.trim_end_matches('/'));
+
+    let client = Client::new();
+
+    let response = client
+        .post(&url)
+        .json(payload)
+        .send()
+        .await
+        .context("failed to reach ollama")?
+        .error_for_status()?;
+
+    let body: Value = response.json().await?;
+    body["response"]
+        .as_str()
+        .map(|x| x.trim().to_string())
+        .ok_or_else(|| anyhow!("response not found in ollama reply"))
+}
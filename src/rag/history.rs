@@ -0,0 +1,40 @@
+use sqlx::{Pool, Postgres};
+
+/// Sent timestamp of the newest message of `thread` already backfilled, if any.
+pub async fn load_checkpoint(
+    pool: &Pool<Postgres>,
+    thread: &str,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar("SELECT last_sent_at FROM history_checkpoints WHERE thread_id = $1")
+        .bind(thread)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn save_checkpoint(
+    pool: &Pool<Postgres>,
+    thread: &str,
+    last_sent_at: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO history_checkpoints (thread_id, last_sent_at)
+        VALUES ($1, $2)
+        ON CONFLICT (thread_id) DO UPDATE
+            SET last_sent_at = EXCLUDED.last_sent_at, updated_at = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(thread)
+    .bind(last_sent_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Forgets every checkpoint so the next backfill reads all threads from the start.
+pub async fn clear_checkpoints(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM history_checkpoints")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
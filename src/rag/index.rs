@@ -0,0 +1,148 @@
+use clap::{Args, ValueEnum};
+use sqlx::{Pool, Postgres, Transaction};
+
+pub const INDEX_NAME: &str = "embeddings_embedding_idx";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IndexKind {
+    /// StreamingDiskANN from pgvectorscale.
+    Diskann,
+    /// HNSW from pgvector.
+    Hnsw,
+    /// IVFFlat from pgvector. Build it after the table is populated.
+    Ivfflat,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct IndexParams {
+    #[clap(long, default_value_t = 16, help = "hnsw: connections per layer")]
+    pub m: i32,
+    #[clap(
+        long,
+        default_value_t = 64,
+        help = "hnsw: candidate list size while building"
+    )]
+    pub ef_construction: i32,
+    #[clap(long, default_value_t = 100, help = "ivfflat: number of lists")]
+    pub lists: i32,
+    #[clap(long, default_value_t = 50, help = "diskann: neighbours per node")]
+    pub num_neighbors: i32,
+    #[clap(
+        long,
+        default_value_t = 100,
+        help = "diskann: candidate list size while building"
+    )]
+    pub search_list_size: i32,
+    #[clap(
+        long,
+        default_value_t = 1.2,
+        help = "diskann: alpha of the pruning algorithm"
+    )]
+    pub max_alpha: f64,
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        IndexParams {
+            m: 16,
+            ef_construction: 64,
+            lists: 100,
+            num_neighbors: 50,
+            search_list_size: 100,
+            max_alpha: 1.2,
+        }
+    }
+}
+
+/// Query-time settings of the approximate indexes; `None` keeps the server default.
+#[derive(Args, Clone, Debug, Default)]
+pub struct QueryTuning {
+    #[clap(long, help = "hnsw: candidate list size while searching")]
+    pub ef_search: Option<i32>,
+    #[clap(long, help = "ivfflat: number of lists to probe")]
+    pub probes: Option<i32>,
+    #[clap(
+        long,
+        help = "diskann: number of candidates to rescore with full vectors"
+    )]
+    pub rescore: Option<i32>,
+    #[clap(long, help = "diskann: candidate list size while searching")]
+    pub query_search_list_size: Option<i32>,
+}
+
+impl QueryTuning {
+    /// Applies the settings for the rest of `tx` only.
+    pub async fn apply(&self, tx: &mut Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+        let settings = [
+            ("hnsw.ef_search", self.ef_search),
+            ("ivfflat.probes", self.probes),
+            ("diskann.query_rescore", self.rescore),
+            (
+                "diskann.query_search_list_size",
+                self.query_search_list_size,
+            ),
+        ];
+        for (name, value) in settings {
+            if let Some(value) = value {
+                sqlx::query("SELECT set_config($1, $2, true)")
+                    .bind(name)
+                    .bind(value.to_string())
+                    .execute(&mut **tx)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drops any existing similarity index and builds a new one for cosine distance.
+pub async fn create_index(
+    pool: &Pool<Postgres>,
+    kind: IndexKind,
+    params: &IndexParams,
+) -> Result<(), sqlx::Error> {
+    let (method, options) = match kind {
+        IndexKind::Diskann => (
+            "diskann",
+            format!(
+                "num_neighbors = {}, search_list_size = {}, max_alpha = {}",
+                params.num_neighbors, params.search_list_size, params.max_alpha
+            ),
+        ),
+        IndexKind::Hnsw => (
+            "hnsw",
+            format!(
+                "m = {}, ef_construction = {}",
+                params.m, params.ef_construction
+            ),
+        ),
+        IndexKind::Ivfflat => ("ivfflat", format!("lists = {}", params.lists)),
+    };
+
+    sqlx::query(&format!("DROP INDEX IF EXISTS {INDEX_NAME}"))
+        .execute(pool)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE INDEX {INDEX_NAME} ON embeddings USING {method} (embedding vector_cosine_ops) WITH ({options})"
+    ))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Rebuilds the existing index with its current settings, e.g. after a bulk import.
+pub async fn rebuild_index(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!("REINDEX INDEX {INDEX_NAME}"))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// The `CREATE INDEX` statement of the current index, if there is one.
+pub async fn describe_index(pool: &Pool<Postgres>) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT indexdef FROM pg_indexes WHERE indexname = $1")
+        .bind(INDEX_NAME)
+        .fetch_optional(pool)
+        .await
+}
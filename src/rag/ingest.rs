@@ -0,0 +1,314 @@
+use anyhow::Context as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, warn};
+
+use crate::rag::attachments::attachment_rows;
+use crate::rag::dataframes::process_dataframe;
+use crate::rag::edits::apply_edit;
+use crate::rag::encryption::seal_rows;
+use crate::rag::sqlx::{insert_embeddings_into_db, message_deleted, message_exists};
+use crate::rag::transcribe::transcribe_voice_notes;
+use crate::rag::vector_db::VectorDb;
+use crate::signal::format_message::MessageKind;
+use crate::signal::process_incoming_message::ProcessedMessage;
+
+#[derive(Args, Clone, Debug)]
+pub struct IngestConfig {
+    #[clap(
+        long = "ingest-queue",
+        default_value_t = 1024,
+        help = "Messages waiting to be embedded before receiving slows down"
+    )]
+    pub queue: usize,
+    #[clap(
+        long = "ingest-batch-size",
+        default_value_t = 32,
+        help = "Messages embedded and inserted together"
+    )]
+    pub batch_size: usize,
+    #[clap(
+        long = "ingest-concurrency",
+        default_value_t = 4,
+        help = "Batches sent to the embedding model at the same time"
+    )]
+    pub concurrency: usize,
+    #[clap(
+        long = "ingest-flush-ms",
+        default_value_t = 500,
+        help = "Longest wait for a batch to fill up"
+    )]
+    pub flush_ms: u64,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            queue: 1024,
+            batch_size: 32,
+            concurrency: 4,
+            flush_ms: 500,
+        }
+    }
+}
+
+/// Which kinds of message are stored, and which of those are embedded for search.
+#[derive(Args, Clone, Debug)]
+pub struct StoragePolicy {
+    #[clap(
+        long = "persist-kind",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = StoragePolicy::DEFAULT_KINDS,
+        help = "Kinds of message stored in the database"
+    )]
+    pub persist: Vec<MessageKind>,
+    #[clap(
+        long = "embed-kind",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = StoragePolicy::DEFAULT_KINDS,
+        help = "Kinds of stored message that are embedded; others are kept without a vector"
+    )]
+    pub embed: Vec<MessageKind>,
+}
+
+impl Default for StoragePolicy {
+    fn default() -> Self {
+        StoragePolicy {
+            persist: StoragePolicy::DEFAULT_KINDS.to_vec(),
+            embed: StoragePolicy::DEFAULT_KINDS.to_vec(),
+        }
+    }
+}
+
+impl StoragePolicy {
+    const DEFAULT_KINDS: [MessageKind; 5] = [
+        MessageKind::Text,
+        MessageKind::Quote,
+        MessageKind::Edit,
+        MessageKind::Attachment,
+        MessageKind::Extracted,
+    ];
+
+    pub fn persists(&self, kind: MessageKind) -> bool {
+        self.persist.contains(&kind)
+    }
+
+    pub fn embeds(&self, kind: MessageKind) -> bool {
+        self.persists(kind) && self.embed.contains(&kind)
+    }
+}
+
+/// Handle to the background worker that embeds and stores messages.
+#[derive(Clone)]
+pub struct Ingest {
+    tx: mpsc::Sender<ProcessedMessage>,
+}
+
+impl Ingest {
+    /// Starts the worker. It stops, after storing everything queued, once every
+    /// `Ingest` handle is dropped.
+    pub fn spawn(vector_db: VectorDb, config: &IngestConfig) -> (Ingest, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(config.queue.max(1));
+        let worker = tokio::spawn(run_worker(vector_db, config.clone(), rx));
+        (Ingest { tx }, worker)
+    }
+
+    /// Queues a message, waiting only when the queue is full.
+    pub async fn enqueue(&self, message: ProcessedMessage) {
+        if self.tx.capacity() == 0 {
+            warn!("ingest queue is full, waiting for the embedding model");
+        }
+        if self.tx.send(message).await.is_err() {
+            error!("ingest worker has stopped, message not stored");
+        }
+    }
+}
+
+async fn run_worker(
+    vector_db: VectorDb,
+    config: IngestConfig,
+    mut rx: mpsc::Receiver<ProcessedMessage>,
+) {
+    let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let batch_size = config.batch_size.max(1);
+    let mut tasks = JoinSet::new();
+
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(Duration::from_millis(config.flush_ms));
+        tokio::pin!(deadline);
+
+        while batch.len() < batch_size {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) => batch.push(message),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        // The message an edit targets may be in a batch still being stored
+        let edits = batch
+            .iter()
+            .any(|message| message.kind == MessageKind::Edit);
+        if edits {
+            while tasks.join_next().await.is_some() {}
+        }
+
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let vector_db = vector_db.clone();
+        tasks.spawn(async move {
+            if let Err(error) = store_batch(&vector_db, batch).await {
+                error!(%error, "failed to store messages");
+            }
+            drop(permit);
+        });
+
+        // Reap finished batches so the set does not grow
+        while tasks.try_join_next().is_some() {}
+    }
+
+    while tasks.join_next().await.is_some() {}
+}
+
+/// Embeds and inserts `messages`, skipping any that are already stored, then applies
+/// the edits among them in order, so an edit finds an original from the same batch.
+pub async fn store_batch(
+    vector_db: &VectorDb,
+    messages: Vec<ProcessedMessage>,
+) -> anyhow::Result<()> {
+    let (edits, messages): (Vec<_>, Vec<_>) = messages
+        .into_iter()
+        .partition(|message| message.kind == MessageKind::Edit);
+    store_messages(vector_db, messages).await?;
+    for edit in edits {
+        if let Err(error) = apply_edit(vector_db, edit).await {
+            error!(%error, "failed to apply edit");
+        }
+    }
+    Ok(())
+}
+
+async fn store_messages(
+    vector_db: &VectorDb,
+    messages: Vec<ProcessedMessage>,
+) -> anyhow::Result<()> {
+    let mut new_messages = Vec::with_capacity(messages.len());
+    for message in messages {
+        // Already stored, e.g. seen by `send`'s synchronization and again by `receive`
+        if let (Some(sender), Some(sent_at)) = (&message.sender, message.sent_at) {
+            if let Ok(true) = message_exists(&vector_db.pool, sender, sent_at as i64).await {
+                continue;
+            }
+            // Deleted for everyone while it waited in the queue
+            if !vector_db.retention.legal_hold {
+                if let Ok(true) = message_deleted(&vector_db.pool, sender, sent_at as i64).await {
+                    continue;
+                }
+            }
+        }
+        new_messages.push(message);
+    }
+    if new_messages.is_empty() {
+        return Ok(());
+    }
+    transcribe_voice_notes(
+        &vector_db.attachments,
+        vector_db.cipher.as_ref(),
+        &mut new_messages,
+    )
+    .await;
+
+    let mut messages_with_embedding = process_dataframe(
+        &new_messages,
+        &*vector_db.embedder,
+        &vector_db.chunking,
+        &vector_db.policy,
+    )
+    .await
+    .with_context(|| format!("failed to embed {} messages", new_messages.len()))?;
+    messages_with_embedding.extend(attachment_rows(vector_db, &new_messages).await?);
+    seal_rows(vector_db.cipher.as_ref(), &mut messages_with_embedding);
+
+    insert_embeddings_into_db(&vector_db.pool, messages_with_embedding)
+        .await
+        .context("failed to insert messages")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn by_default_messages_with_text_are_stored_and_embedded() {
+        let policy = StoragePolicy::default();
+        for kind in [
+            MessageKind::Text,
+            MessageKind::Quote,
+            MessageKind::Edit,
+            MessageKind::Attachment,
+            MessageKind::Extracted,
+        ] {
+            assert!(policy.persists(kind), "{kind:?}");
+            assert!(policy.embeds(kind), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn by_default_events_are_dropped() {
+        let policy = StoragePolicy::default();
+        for kind in [
+            MessageKind::Reaction,
+            MessageKind::Delete,
+            MessageKind::Receipt,
+            MessageKind::Typing,
+            MessageKind::Call,
+            MessageKind::Story,
+            MessageKind::Sync,
+            MessageKind::Null,
+            MessageKind::Error,
+        ] {
+            assert!(!policy.persists(kind), "{kind:?}");
+            assert!(!policy.embeds(kind), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn flags_default_to_the_policy_default() {
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            policy: StoragePolicy,
+        }
+        let parsed = Cli::parse_from(["signal-vector-db"]).policy;
+        let default = StoragePolicy::default();
+        assert_eq!(parsed.persist, default.persist);
+        assert_eq!(parsed.embed, default.embed);
+    }
+
+    #[test]
+    fn only_stored_kinds_are_embedded() {
+        let policy = StoragePolicy {
+            persist: vec![MessageKind::Text, MessageKind::Reaction],
+            embed: vec![MessageKind::Text, MessageKind::Typing],
+        };
+        assert!(policy.embeds(MessageKind::Text));
+        assert!(policy.persists(MessageKind::Reaction));
+        assert!(!policy.embeds(MessageKind::Reaction));
+        assert!(!policy.embeds(MessageKind::Typing));
+    }
+}
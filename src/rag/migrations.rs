@@ -0,0 +1,376 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use tracing::info;
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    /// `{dimension}` is replaced with the vector dimension of the embedder in use.
+    pub sql: &'static str,
+}
+
+/// Applied in order, each exactly once. Never edit a migration that has shipped;
+/// append a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create embeddings table",
+        sql: r#"
+        CREATE EXTENSION IF NOT EXISTS vector;
+        CREATE EXTENSION IF NOT EXISTS vectorscale CASCADE;
+
+        CREATE TABLE IF NOT EXISTS embeddings (
+            id bigserial primary key,
+            body text,
+            direction text,
+            contact text,
+            group_name text,
+            attachments text,
+            tokens integer,
+            embedding VECTOR({dimension}),
+            created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        "#,
+    },
+    Migration {
+        version: 2,
+        description: "store signal message identity",
+        sql: r#"
+        ALTER TABLE embeddings
+            ADD COLUMN IF NOT EXISTS sent_at bigint,
+            ADD COLUMN IF NOT EXISTS sender_aci text,
+            ADD COLUMN IF NOT EXISTS thread_id text,
+            ADD COLUMN IF NOT EXISTS server_guid text,
+            ADD COLUMN IF NOT EXISTS chunk_index integer NOT NULL DEFAULT 0;
+
+        CREATE UNIQUE INDEX IF NOT EXISTS embeddings_message_key
+            ON embeddings (sender_aci, sent_at, chunk_index);
+        "#,
+    },
+    Migration {
+        version: 3,
+        description: "record chunk positions",
+        sql: r#"
+        ALTER TABLE embeddings
+            ADD COLUMN IF NOT EXISTS chunk_count integer NOT NULL DEFAULT 1,
+            ADD COLUMN IF NOT EXISTS char_start integer,
+            ADD COLUMN IF NOT EXISTS char_end integer;
+
+        -- Before version 3 every chunk of a long message stored the full body
+        -- and embedding; keep the first copy as a single-chunk message.
+        DELETE FROM embeddings
+            WHERE chunk_index > 0 AND sender_aci IS NOT NULL AND sent_at IS NOT NULL;
+        "#,
+    },
+    Migration {
+        version: 4,
+        description: "create conversation windows",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS conversation_windows (
+            id bigserial primary key,
+            thread_id text NOT NULL,
+            contact text,
+            group_name text,
+            started_at bigint NOT NULL,
+            ended_at bigint NOT NULL,
+            message_count integer NOT NULL,
+            body text NOT NULL,
+            tokens integer NOT NULL,
+            embedding VECTOR({dimension}),
+            created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (thread_id, started_at, ended_at, message_count)
+        );
+
+        CREATE TABLE IF NOT EXISTS conversation_window_messages (
+            window_id bigint NOT NULL REFERENCES conversation_windows (id) ON DELETE CASCADE,
+            embedding_id bigint NOT NULL REFERENCES embeddings (id) ON DELETE CASCADE,
+            position integer NOT NULL,
+            PRIMARY KEY (window_id, embedding_id)
+        );
+
+        CREATE INDEX IF NOT EXISTS conversation_window_messages_embedding_idx
+            ON conversation_window_messages (embedding_id);
+        "#,
+    },
+    Migration {
+        version: 5,
+        description: "record message kind",
+        sql: r#"
+        ALTER TABLE embeddings ADD COLUMN IF NOT EXISTS kind text;
+
+        -- Only texts, quotes and edits were stored before, and edits read as texts
+        UPDATE embeddings
+            SET kind = CASE WHEN body LIKE 'Answer to message "%' THEN 'quote' ELSE 'text' END
+            WHERE kind IS NULL;
+        "#,
+    },
+    Migration {
+        version: 6,
+        description: "keep message revisions",
+        sql: r#"
+        ALTER TABLE embeddings
+            ADD COLUMN IF NOT EXISTS revision integer NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS edited_at bigint;
+
+        CREATE TABLE IF NOT EXISTS message_revisions (
+            id bigserial primary key,
+            sender_aci text NOT NULL,
+            sent_at bigint NOT NULL,
+            revision integer NOT NULL,
+            chunk_index integer NOT NULL,
+            body text,
+            tokens integer,
+            embedding VECTOR({dimension}),
+            replaced_at bigint NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (sender_aci, sent_at, revision, chunk_index)
+        );
+        "#,
+    },
+    Migration {
+        version: 7,
+        description: "honor deletes and disappearing messages",
+        sql: r#"
+        ALTER TABLE embeddings ADD COLUMN IF NOT EXISTS expires_at bigint;
+        CREATE INDEX IF NOT EXISTS embeddings_expires_at_idx
+            ON embeddings (expires_at) WHERE expires_at IS NOT NULL;
+
+        CREATE TABLE IF NOT EXISTS deleted_messages (
+            sender_aci text NOT NULL,
+            sent_at bigint NOT NULL,
+            deleted_at bigint NOT NULL,
+            PRIMARY KEY (sender_aci, sent_at)
+        );
+
+        CREATE TABLE IF NOT EXISTS thread_expiry (
+            thread_id text primary key,
+            expire_timer integer NOT NULL,
+            updated_at bigint NOT NULL
+        );
+        "#,
+    },
+    Migration {
+        version: 8,
+        description: "store reactions",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS reactions (
+            id bigserial primary key,
+            target_sender_aci text NOT NULL,
+            target_sent_at bigint NOT NULL,
+            reactor_aci text NOT NULL,
+            from_me boolean NOT NULL,
+            emoji text NOT NULL,
+            removed boolean NOT NULL DEFAULT false,
+            reacted_at bigint NOT NULL,
+            thread_id text,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (target_sender_aci, target_sent_at, reactor_aci)
+        );
+        "#,
+    },
+    Migration {
+        version: 9,
+        description: "link replies to the message they quote",
+        sql: r#"
+        ALTER TABLE embeddings
+            ADD COLUMN IF NOT EXISTS reply_to_sender_aci text,
+            ADD COLUMN IF NOT EXISTS reply_to_sent_at bigint;
+
+        CREATE INDEX IF NOT EXISTS embeddings_reply_to_idx
+            ON embeddings (reply_to_sender_aci, reply_to_sent_at)
+            WHERE reply_to_sent_at IS NOT NULL;
+        "#,
+    },
+    Migration {
+        version: 10,
+        description: "track history backfill per thread",
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS history_checkpoints (
+            thread_id text primary key,
+            last_sent_at bigint NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        "#,
+    },
+    Migration {
+        version: 11,
+        description: "record the embedding model of every vector",
+        sql: r#"
+        ALTER TABLE embeddings ADD COLUMN IF NOT EXISTS embedding_model text;
+        ALTER TABLE conversation_windows ADD COLUMN IF NOT EXISTS embedding_model text;
+        ALTER TABLE message_revisions ADD COLUMN IF NOT EXISTS embedding_model text;
+
+        -- `active` is the model queries are embedded with; `building` is the one
+        -- `reembed` is filling the shadow columns for
+        CREATE TABLE IF NOT EXISTS embedding_models (
+            model text primary key,
+            dimension integer NOT NULL,
+            active boolean NOT NULL DEFAULT false,
+            building boolean NOT NULL DEFAULT false,
+            started_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            activated_at TIMESTAMPTZ
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS embedding_models_active_idx
+            ON embedding_models (active) WHERE active;
+        CREATE UNIQUE INDEX IF NOT EXISTS embedding_models_building_idx
+            ON embedding_models (building) WHERE building;
+        "#,
+    },
+    Migration {
+        version: 12,
+        description: "index the text of attachments",
+        sql: r#"
+        -- `source` says how a row's text was obtained; `attachment` names the file
+        -- it came from, empty for the message body itself
+        ALTER TABLE embeddings
+            ADD COLUMN IF NOT EXISTS source text NOT NULL DEFAULT 'message',
+            ADD COLUMN IF NOT EXISTS attachment text NOT NULL DEFAULT '';
+
+        DROP INDEX IF EXISTS embeddings_message_key;
+        CREATE UNIQUE INDEX IF NOT EXISTS embeddings_message_key
+            ON embeddings (sender_aci, sent_at, attachment, chunk_index);
+        "#,
+    },
+    Migration {
+        version: 13,
+        description: "keep text read from an image apart from its caption",
+        sql: r#"
+        DROP INDEX IF EXISTS embeddings_message_key;
+        CREATE UNIQUE INDEX IF NOT EXISTS embeddings_message_key
+            ON embeddings (sender_aci, sent_at, attachment, source, chunk_index);
+        "#,
+    },
+    Migration {
+        version: 14,
+        description: "record the language of transcribed voice notes",
+        sql: r#"
+        ALTER TABLE embeddings ADD COLUMN IF NOT EXISTS language text;
+        "#,
+    },
+    Migration {
+        version: 15,
+        description: "record attachments by content digest",
+        sql: r#"
+        -- Files are stored once per digest; each message that carried one gets a row
+        CREATE TABLE IF NOT EXISTS attachments (
+            id bigserial PRIMARY KEY,
+            sha256 text NOT NULL,
+            path text NOT NULL,
+            mime_type text,
+            size bigint NOT NULL,
+            file_name text,
+            width integer,
+            height integer,
+            voice_note boolean NOT NULL DEFAULT false,
+            sender_aci text,
+            sent_at bigint,
+            created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (sha256, sender_aci, sent_at)
+        );
+
+        CREATE INDEX IF NOT EXISTS attachments_message
+            ON attachments (sender_aci, sent_at);
+        "#,
+    },
+    Migration {
+        version: 16,
+        description: "set up encryption at rest",
+        sql: r#"
+        -- At most one row: the key derivation salt and a value sealed with the key
+        CREATE TABLE IF NOT EXISTS encryption (
+            id boolean PRIMARY KEY DEFAULT true CHECK (id),
+            salt bytea NOT NULL,
+            verifier bytea NOT NULL,
+            created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+        );
+        "#,
+    },
+];
+
+// Arbitrary key so concurrent processes never apply the same migration twice
+const MIGRATION_LOCK: i64 = 0x5349_474e_414c;
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: &'static str,
+    pub applied_at: Option<DateTime<Utc>>,
+}
+
+/// Applies every pending migration and returns the versions that were applied.
+pub async fn run_migrations(
+    pool: &Pool<Postgres>,
+    dimension: usize,
+) -> Result<Vec<i64>, sqlx::Error> {
+    create_schema_migrations(pool).await?;
+
+    let mut applied = vec![];
+    for migration in MIGRATIONS {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(&mut *tx)
+            .await?;
+
+        let done: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM schema_migrations WHERE version = $1)")
+                .bind(migration.version)
+                .fetch_one(&mut *tx)
+                .await?;
+        if done {
+            continue;
+        }
+
+        info!(
+            version = migration.version,
+            migration.description, "applying migration"
+        );
+        let sql = migration.sql.replace("{dimension}", &dimension.to_string());
+        sqlx::raw_sql(&sql).execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO schema_migrations (version, description) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+pub async fn migration_status(pool: &Pool<Postgres>) -> Result<Vec<MigrationStatus>, sqlx::Error> {
+    create_schema_migrations(pool).await?;
+
+    let applied: Vec<(i64, DateTime<Utc>)> =
+        sqlx::query_as("SELECT version, applied_at FROM schema_migrations")
+            .fetch_all(pool)
+            .await?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            description: migration.description,
+            applied_at: applied
+                .iter()
+                .find(|(version, _)| *version == migration.version)
+                .map(|(_, applied_at)| *applied_at),
+        })
+        .collect())
+}
+
+async fn create_schema_migrations(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version bigint primary key,
+            description text NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -1,4 +1,25 @@
+pub mod ask;
+pub mod attachment_store;
+pub mod attachments;
 pub mod dataframes;
+pub mod edits;
+pub mod embedder;
+pub mod encryption;
+pub mod export;
+pub mod generate;
+pub mod history;
+pub mod index;
+pub mod ingest;
+pub mod migrations;
+pub mod parquet;
 pub mod prompt_template;
 pub mod questions;
-pub mod sqlx;
\ No newline at end of file
+pub mod reactions;
+pub mod reembed;
+pub mod retention;
+pub mod search;
+pub mod sqlx;
+pub mod threads;
+pub mod transcribe;
+pub mod vector_db;
+pub mod windows;
@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, Int32Array, Int64Array, ListArray, StringArray};
+use arrow::datatypes::{DataType, Field, Float32Type, Int32Type, Int64Type, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+
+use crate::rag::export::{ExportedMessage, EXPORT_COLUMNS};
+
+/// Writes exported rows to a Parquet file, one row group per [`ParquetExport::write`].
+pub struct ParquetExport {
+    writer: ArrowWriter<File>,
+    schema: SchemaRef,
+}
+
+impl ParquetExport {
+    pub fn new(file: File) -> anyhow::Result<Self> {
+        let schema = Arc::new(schema());
+        let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+        Ok(ParquetExport { writer, schema })
+    }
+
+    pub fn write(&mut self, rows: &[ExportedMessage]) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let columns: Vec<ArrayRef> = vec![
+            strings(rows, |row| &row.thread_id),
+            strings(rows, |row| &row.sender_aci),
+            int64s(rows, |row| row.sent_at),
+            strings(rows, |row| &row.kind),
+            strings(rows, |row| &row.direction),
+            strings(rows, |row| &row.contact),
+            strings(rows, |row| &row.group_name),
+            strings(rows, |row| &row.body),
+            strings(rows, |row| &row.attachments),
+            strings(rows, |row| &row.source),
+            strings(rows, |row| &row.attachment),
+            strings(rows, |row| &row.language),
+            int32s(rows, |row| row.tokens),
+            int32s(rows, |row| Some(row.chunk_index)),
+            int32s(rows, |row| Some(row.chunk_count)),
+            int32s(rows, |row| row.char_start),
+            int32s(rows, |row| row.char_end),
+            int32s(rows, |row| Some(row.revision)),
+            int64s(rows, |row| row.edited_at),
+            int64s(rows, |row| row.expires_at),
+            strings(rows, |row| &row.reply_to_sender_aci),
+            int64s(rows, |row| row.reply_to_sent_at),
+            strings(rows, |row| &row.embedding_model),
+            Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(
+                rows.iter().map(|row| {
+                    row.embedding
+                        .as_ref()
+                        .map(|embedding| embedding.iter().copied().map(Some).collect::<Vec<_>>())
+                }),
+            )),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    pub fn close(self) -> anyhow::Result<()> {
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+/// Reads a file written by [`ParquetExport`] in batches of up to `batch_size` rows;
+/// columns it lacks are left empty.
+pub fn read_parquet(
+    file: File,
+    batch_size: usize,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Vec<ExportedMessage>>>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)?
+        .with_batch_size(batch_size)
+        .build()?;
+    Ok(reader.map(|batch| Ok(rows_from_batch(&batch?))))
+}
+
+fn rows_from_batch(batch: &RecordBatch) -> Vec<ExportedMessage> {
+    (0..batch.num_rows())
+        .map(|i| ExportedMessage {
+            thread_id: string_at(batch, "thread_id", i),
+            sender_aci: string_at(batch, "sender_aci", i),
+            sent_at: int64_at(batch, "sent_at", i),
+            kind: string_at(batch, "kind", i),
+            direction: string_at(batch, "direction", i),
+            contact: string_at(batch, "contact", i),
+            group_name: string_at(batch, "group_name", i),
+            body: string_at(batch, "body", i),
+            attachments: string_at(batch, "attachments", i),
+            source: string_at(batch, "source", i),
+            attachment: string_at(batch, "attachment", i),
+            language: string_at(batch, "language", i),
+            tokens: int32_at(batch, "tokens", i),
+            chunk_index: int32_at(batch, "chunk_index", i).unwrap_or(0),
+            chunk_count: int32_at(batch, "chunk_count", i).unwrap_or(1),
+            char_start: int32_at(batch, "char_start", i),
+            char_end: int32_at(batch, "char_end", i),
+            revision: int32_at(batch, "revision", i).unwrap_or(0),
+            edited_at: int64_at(batch, "edited_at", i),
+            expires_at: int64_at(batch, "expires_at", i),
+            reply_to_sender_aci: string_at(batch, "reply_to_sender_aci", i),
+            reply_to_sent_at: int64_at(batch, "reply_to_sent_at", i),
+            embedding_model: string_at(batch, "embedding_model", i),
+            embedding: embedding_at(batch, i),
+        })
+        .collect()
+}
+
+fn schema() -> Schema {
+    let fields = EXPORT_COLUMNS.iter().map(|name| {
+        let data_type = match *name {
+            "sent_at" | "edited_at" | "expires_at" | "reply_to_sent_at" => DataType::Int64,
+            "tokens" | "chunk_index" | "chunk_count" | "char_start" | "char_end" | "revision" => {
+                DataType::Int32
+            }
+            "embedding" => DataType::List(Arc::new(Field::new_list_field(DataType::Float32, true))),
+            _ => DataType::Utf8,
+        };
+        Field::new(*name, data_type, true)
+    });
+    Schema::new(fields.collect::<Vec<_>>())
+}
+
+fn strings(
+    rows: &[ExportedMessage],
+    value: impl Fn(&ExportedMessage) -> &Option<String>,
+) -> ArrayRef {
+    Arc::new(
+        rows.iter()
+            .map(|row| value(row).as_deref())
+            .collect::<StringArray>(),
+    )
+}
+
+fn int32s(rows: &[ExportedMessage], value: impl Fn(&ExportedMessage) -> Option<i32>) -> ArrayRef {
+    Arc::new(rows.iter().map(value).collect::<Int32Array>())
+}
+
+fn int64s(rows: &[ExportedMessage], value: impl Fn(&ExportedMessage) -> Option<i64>) -> ArrayRef {
+    Arc::new(rows.iter().map(value).collect::<Int64Array>())
+}
+
+fn string_at(batch: &RecordBatch, name: &str, i: usize) -> Option<String> {
+    let column = batch.column_by_name(name)?.as_string_opt::<i32>()?;
+    column.is_valid(i).then(|| column.value(i).to_string())
+}
+
+fn int32_at(batch: &RecordBatch, name: &str, i: usize) -> Option<i32> {
+    let column = batch
+        .column_by_name(name)?
+        .as_primitive_opt::<Int32Type>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn int64_at(batch: &RecordBatch, name: &str, i: usize) -> Option<i64> {
+    let column = batch
+        .column_by_name(name)?
+        .as_primitive_opt::<Int64Type>()?;
+    column.is_valid(i).then(|| column.value(i))
+}
+
+fn embedding_at(batch: &RecordBatch, i: usize) -> Option<Vec<f32>> {
+    let column = batch.column_by_name("embedding")?.as_list_opt::<i32>()?;
+    if !column.is_valid(i) {
+        return None;
+    }
+    let values = column.value(i);
+    Some(values.as_primitive_opt::<Float32Type>()?.values().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Vec<ExportedMessage> {
+        vec![
+            ExportedMessage {
+                thread_id: Some(String::from("aci-alice")),
+                sender_aci: Some(String::from("aci-alice")),
+                sent_at: Some(1_700_000_000_000),
+                kind: Some(String::from("text")),
+                direction: Some(String::from("from")),
+                contact: Some(String::from("Alice")),
+                body: Some(String::from("héllo, wörld 🙂")),
+                source: Some(String::from("message")),
+                tokens: Some(6),
+                chunk_index: 1,
+                chunk_count: 2,
+                char_start: Some(10),
+                char_end: Some(25),
+                revision: 1,
+                edited_at: Some(1_700_000_001_000),
+                expires_at: Some(1_700_000_060_000),
+                reply_to_sender_aci: Some(String::from("aci-bob")),
+                reply_to_sent_at: Some(1_600_000_000_000),
+                embedding_model: Some(String::from("fake")),
+                embedding: Some(vec![0.25, -0.5, 1.0]),
+                ..ExportedMessage::default()
+            },
+            // Kept without a vector, and with every optional column empty
+            ExportedMessage {
+                kind: Some(String::from("reaction")),
+                chunk_count: 1,
+                ..ExportedMessage::default()
+            },
+            ExportedMessage {
+                group_name: Some(String::from("Trip")),
+                attachments: Some(String::from("ab/abcdef.pdf")),
+                source: Some(String::from("pdf")),
+                attachment: Some(String::from("ab/abcdef.pdf")),
+                language: Some(String::from("fr")),
+                chunk_count: 1,
+                embedding: Some(vec![]),
+                ..ExportedMessage::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn rows_survive_a_round_trip() {
+        let rows = rows();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut export = ParquetExport::new(file.reopen().unwrap()).unwrap();
+        export.write(&rows[..1]).unwrap();
+        export.write(&[]).unwrap();
+        export.write(&rows[1..]).unwrap();
+        export.close().unwrap();
+
+        let batches = read_parquet(file.reopen().unwrap(), 2)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert!(batches.iter().all(|batch| batch.len() <= 2));
+        let read: Vec<ExportedMessage> = batches.concat();
+        assert_eq!(
+            serde_json::to_value(&read).unwrap(),
+            serde_json::to_value(&rows).unwrap()
+        );
+    }
+}
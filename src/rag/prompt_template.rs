@@ -11,6 +11,10 @@ pub fn llama3(user_message: &str) -> String {
     let system_prompt = "You are a friendly and useful Chatbot. Be of assistance the best you can.
 ";
 
+    llama3_with_system(system_prompt, user_message)
+}
+
+pub fn llama3_with_system(system_prompt: &str, user_message: &str) -> String {
     format!(
         "
 <|begin_of_text|>
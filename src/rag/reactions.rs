@@ -0,0 +1,53 @@
+use anyhow::bail;
+use sqlx::{Pool, Postgres};
+
+use crate::signal::format_message::Direction;
+use crate::signal::process_incoming_message::ProcessedMessage;
+
+/// Emoji compared without variation selectors, so `❤️` and `❤` are the same reaction.
+pub fn normalize_emoji(emoji: &str) -> String {
+    emoji.replace('\u{FE0F}', "")
+}
+
+/// Records, replaces or takes back the reaction of one person to one message.
+///
+/// Reactions point at their target by its identity (author, sent timestamp), so
+/// they also apply to messages stored after the reaction arrived.
+pub async fn record_reaction(
+    pool: &Pool<Postgres>,
+    reaction: &ProcessedMessage,
+) -> anyhow::Result<()> {
+    let (Some(reactor), Some(target_author), Some(target), Some(emoji), Some(reacted_at)) = (
+        &reaction.sender,
+        &reaction.target_author_aci,
+        reaction.target_sent_at,
+        &reaction.emoji,
+        reaction.sent_at,
+    ) else {
+        bail!("reaction without reactor, target or emoji");
+    };
+
+    // Signal keeps one reaction per person and message; the newest wins
+    sqlx::query(
+        r#"
+        INSERT INTO reactions
+            (target_sender_aci, target_sent_at, reactor_aci, from_me, emoji, removed, reacted_at, thread_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (target_sender_aci, target_sent_at, reactor_aci) DO UPDATE
+            SET emoji = EXCLUDED.emoji, removed = EXCLUDED.removed, reacted_at = EXCLUDED.reacted_at
+            WHERE reactions.reacted_at < EXCLUDED.reacted_at
+        "#,
+    )
+    .bind(target_author)
+    .bind(target as i64)
+    .bind(reactor)
+    .bind(matches!(reaction.direction, Some(Direction::To)))
+    .bind(normalize_emoji(emoji))
+    .bind(reaction.reaction_removed)
+    .bind(reacted_at as i64)
+    .bind(&reaction.thread)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
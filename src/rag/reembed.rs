@@ -0,0 +1,339 @@
+use std::sync::Arc;
+
+use anyhow::bail;
+use pgvector::Vector;
+use sqlx::{PgConnection, Pool, Postgres};
+use tracing::{info, warn};
+
+use crate::rag::embedder::{Embedder, EmbedderConfig};
+use crate::rag::encryption::Cipher;
+use crate::rag::sqlx::stored_dimension;
+use crate::rag::vector_db::VectorDb;
+
+/// Every table holding vectors, all switched to a new model together.
+const TABLES: [&str; 3] = ["embeddings", "conversation_windows", "message_revisions"];
+
+// Arbitrary key so only one `reembed` fills the shadow columns at a time
+const REEMBED_LOCK: i64 = 0x5245_454d_4244;
+
+#[derive(Debug, Default)]
+pub struct ReembedStats {
+    pub model: String,
+    pub dimension: usize,
+    /// Rows embedded with the new model.
+    pub rows: usize,
+}
+
+/// Builds the embedder for the model the stored vectors were made with.
+///
+/// The first run records the configured model as active. Afterwards only `reembed`
+/// changes it, so a configured model that differs is ignored with a warning instead
+/// of embedding queries into the wrong vector space.
+pub async fn active_embedder(
+    pool: &Pool<Postgres>,
+    config: &EmbedderConfig,
+) -> anyhow::Result<Arc<dyn Embedder>> {
+    let configured = config.build();
+    let dimension = configured.dimension().await?;
+
+    // Concurrent first runs race here; the unique index keeps one active model
+    let registered = sqlx::query(
+        r#"
+        INSERT INTO embedding_models (model, dimension, active, activated_at)
+        SELECT $1, $2, true, CURRENT_TIMESTAMP
+        WHERE NOT EXISTS (SELECT 1 FROM embedding_models WHERE active)
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(configured.model())
+    .bind(dimension as i32)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if registered > 0 {
+        // Vectors stored before models were recorded are the configured model's
+        for table in TABLES {
+            sqlx::query(&format!(
+                "UPDATE {table} SET embedding_model = $1 \
+                    WHERE embedding_model IS NULL AND embedding IS NOT NULL"
+            ))
+            .bind(configured.model())
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    let active: String = sqlx::query_scalar("SELECT model FROM embedding_models WHERE active")
+        .fetch_one(pool)
+        .await?;
+    let (embedder, dimension) = if active == configured.model() {
+        (configured, dimension)
+    } else {
+        warn!(
+            configured = configured.model(),
+            active, "stored vectors use another embedding model; run `reembed` to switch"
+        );
+        let embedder = EmbedderConfig {
+            model: active,
+            ..config.clone()
+        }
+        .build();
+        let dimension = embedder.dimension().await?;
+        (embedder, dimension)
+    };
+
+    let existing = stored_dimension(pool).await?.unwrap_or_default();
+    if existing != dimension as i32 {
+        bail!(
+            "embeddings table stores {existing}-dimensional vectors but {} produces {dimension}",
+            embedder.model()
+        );
+    }
+    Ok(embedder)
+}
+
+/// Re-embeds every stored vector with `model` on the configured backend, then makes
+/// it the active model.
+///
+/// New vectors are written to shadow columns while searches keep using the current
+/// ones, and the columns are swapped in one transaction at the end. An interrupted
+/// run resumes with the rows it had not reached yet.
+pub async fn reembed(
+    vector_db: &VectorDb,
+    config: &EmbedderConfig,
+    model: &str,
+    batch_size: usize,
+) -> anyhow::Result<ReembedStats> {
+    let embedder = EmbedderConfig {
+        model: model.to_string(),
+        ..config.clone()
+    }
+    .build();
+    let dimension = embedder.dimension().await?;
+
+    // Held for the whole run and released when the connection closes
+    let mut lock = vector_db.pool.acquire().await?.detach();
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+        .bind(REEMBED_LOCK)
+        .fetch_one(&mut lock)
+        .await?;
+    if !locked {
+        bail!("another reembed is running");
+    }
+
+    prepare_shadow_columns(&vector_db.pool, embedder.model(), dimension).await?;
+
+    let mut rows = 0;
+    for table in TABLES {
+        loop {
+            let mut tx = vector_db.pool.begin().await?;
+            let filled = fill_batch(
+                &mut tx,
+                &*embedder,
+                vector_db.cipher.as_ref(),
+                table,
+                batch_size,
+            )
+            .await?;
+            tx.commit().await?;
+            if filled == 0 {
+                break;
+            }
+            rows += filled;
+            info!(table, rows, "re-embedded rows");
+        }
+    }
+    rows += switch_model(
+        &vector_db.pool,
+        &*embedder,
+        vector_db.cipher.as_ref(),
+        batch_size,
+    )
+    .await?;
+
+    Ok(ReembedStats {
+        model: embedder.model().to_string(),
+        dimension,
+        rows,
+    })
+}
+
+/// Adds the shadow columns for `model`, keeping those of an interrupted run for the
+/// same model.
+async fn prepare_shadow_columns(
+    pool: &Pool<Postgres>,
+    model: &str,
+    dimension: usize,
+) -> Result<(), sqlx::Error> {
+    let building: Option<(String, i32)> =
+        sqlx::query_as("SELECT model, dimension FROM embedding_models WHERE building")
+            .fetch_optional(pool)
+            .await?;
+    if building == Some((model.to_string(), dimension as i32)) {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+    for table in TABLES {
+        sqlx::raw_sql(&format!(
+            "ALTER TABLE {table} \
+                DROP COLUMN IF EXISTS embedding_next, \
+                DROP COLUMN IF EXISTS embedding_model_next; \
+            ALTER TABLE {table} \
+                ADD COLUMN embedding_next VECTOR({dimension}), \
+                ADD COLUMN embedding_model_next text;"
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("UPDATE embedding_models SET building = false WHERE building")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO embedding_models (model, dimension, building)
+        VALUES ($1, $2, true)
+        ON CONFLICT (model) DO UPDATE
+            SET dimension = EXCLUDED.dimension, building = true, started_at = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(model)
+    .bind(dimension as i32)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Embeds up to `batch_size` rows of `table` that have a current vector but no new
+/// one yet, returning how many.
+async fn fill_batch(
+    conn: &mut PgConnection,
+    embedder: &dyn Embedder,
+    cipher: Option<&Cipher>,
+    table: &str,
+    batch_size: usize,
+) -> anyhow::Result<usize> {
+    // Rows stored without a vector by the storage policy stay without one
+    let rows: Vec<(i64, String)> = sqlx::query_as(&format!(
+        "SELECT id, body FROM {table} \
+            WHERE embedding IS NOT NULL AND embedding_next IS NULL AND body IS NOT NULL \
+            ORDER BY id LIMIT $1"
+    ))
+    .bind(batch_size as i64)
+    .fetch_all(&mut *conn)
+    .await?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let texts: Vec<String> = rows
+        .iter()
+        .map(|(_, body)| match cipher {
+            Some(cipher) => cipher.open_text(body),
+            None => body.clone(),
+        })
+        .collect();
+    let embeddings = embedder.embed_batch(&texts).await?;
+    if embeddings.len() != rows.len() {
+        bail!(
+            "expected {} embeddings, got {}",
+            rows.len(),
+            embeddings.len()
+        );
+    }
+
+    for ((id, _), embedding) in rows.iter().zip(embeddings) {
+        sqlx::query(&format!(
+            "UPDATE {table} SET embedding_next = $1, embedding_model_next = $2 WHERE id = $3"
+        ))
+        .bind(Vector::from(embedding))
+        .bind(embedder.model())
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(rows.len())
+}
+
+/// Embeds what was stored meanwhile and swaps the shadow columns in, returning the
+/// number of rows embedded while writers were blocked.
+async fn switch_model(
+    pool: &Pool<Postgres>,
+    embedder: &dyn Embedder,
+    cipher: Option<&Cipher>,
+    batch_size: usize,
+) -> anyhow::Result<usize> {
+    let mut tx = pool.begin().await?;
+    // Blocks writers, which check the active model once they get past it. Searches go
+    // on until the columns are dropped, which waits for them and then blocks them too
+    // until the commit.
+    sqlx::query(&format!(
+        "LOCK TABLE {} IN SHARE ROW EXCLUSIVE MODE",
+        TABLES.join(", ")
+    ))
+    .execute(&mut *tx)
+    .await?;
+
+    let mut rows = 0;
+    for table in TABLES {
+        loop {
+            let filled = fill_batch(&mut tx, embedder, cipher, table, batch_size).await?;
+            if filled == 0 {
+                break;
+            }
+            rows += filled;
+        }
+    }
+
+    // Vector indexes go with the dropped column; rebuilt on the new one after the commit
+    let indexes: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT pg_get_indexdef(i.indexrelid)
+        FROM pg_index i
+        JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+        WHERE a.attname = 'embedding' AND i.indrelid::regclass::text = ANY($1)
+        "#,
+    )
+    .bind(&TABLES[..])
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for table in TABLES {
+        sqlx::raw_sql(&format!(
+            "ALTER TABLE {table} DROP COLUMN embedding, DROP COLUMN embedding_model; \
+            ALTER TABLE {table} RENAME COLUMN embedding_next TO embedding; \
+            ALTER TABLE {table} RENAME COLUMN embedding_model_next TO embedding_model;"
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("UPDATE embedding_models SET active = false WHERE active")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        UPDATE embedding_models
+            SET active = true, building = false, activated_at = CURRENT_TIMESTAMP
+            WHERE model = $1
+        "#,
+    )
+    .bind(embedder.model())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!(model = embedder.model(), "switched embedding model");
+
+    // Concurrently, so neither searches nor writers wait; they scan until it is ready
+    for index in indexes {
+        let index = index
+            .replacen("CREATE INDEX ", "CREATE INDEX CONCURRENTLY ", 1)
+            .replacen(
+                "CREATE UNIQUE INDEX ",
+                "CREATE UNIQUE INDEX CONCURRENTLY ",
+                1,
+            );
+        info!(index, "rebuilding vector index");
+        sqlx::raw_sql(&index).execute(pool).await?;
+    }
+    Ok(rows)
+}
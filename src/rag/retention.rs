@@ -0,0 +1,292 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use clap::Args;
+use sqlx::{PgConnection, Pool, Postgres};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::rag::vector_db::VectorDb;
+use crate::signal::process_incoming_message::ProcessedMessage;
+
+#[derive(Args, Clone, Debug)]
+pub struct RetentionConfig {
+    #[clap(
+        long = "legal-hold",
+        help = "Keep messages that were deleted for everyone or have disappeared"
+    )]
+    pub legal_hold: bool,
+    #[clap(
+        long = "reap-interval-secs",
+        default_value_t = 60,
+        help = "How often disappeared messages are removed"
+    )]
+    pub reap_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            legal_hold: false,
+            reap_interval_secs: 60,
+        }
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as i64
+}
+
+/// Removes the message `delete` targets, unless under legal hold.
+///
+/// A tombstone is kept either way, so a copy of the message that is still queued
+/// or synced later is not stored again.
+pub async fn apply_delete(vector_db: &VectorDb, delete: &ProcessedMessage) -> anyhow::Result<()> {
+    // Only the author can delete for everyone, so the sender is the target's sender
+    let (Some(sender), Some(target), Some(deleted_at)) =
+        (&delete.sender, delete.target_sent_at, delete.sent_at)
+    else {
+        bail!("delete without sender or target message");
+    };
+
+    let mut tx = vector_db.pool.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT INTO deleted_messages (sender_aci, sent_at, deleted_at) VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(sender)
+    .bind(target as i64)
+    .bind(deleted_at as i64)
+    .execute(&mut *tx)
+    .await?;
+    let mut orphans = vec![];
+    if !vector_db.retention.legal_hold {
+        orphans = purge_message(&mut tx, sender, target as i64).await?;
+    }
+    tx.commit().await?;
+    remove_files(&vector_db.attachments.dir, &orphans).await;
+
+    Ok(())
+}
+
+/// Deletes every trace of one message: its chunks, earlier revisions, reactions,
+/// attachment records and the conversation windows that quote it.
+///
+/// Returns the attachment files no other message shares, to be removed with
+/// [`remove_files`] once the transaction has committed.
+pub async fn purge_message(
+    conn: &mut PgConnection,
+    sender_aci: &str,
+    sent_at: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM conversation_windows WHERE id IN (
+            SELECT m.window_id FROM conversation_window_messages m
+            JOIN embeddings e ON e.id = m.embedding_id
+            WHERE e.sender_aci = $1 AND e.sent_at = $2
+        )
+        "#,
+    )
+    .bind(sender_aci)
+    .bind(sent_at)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("DELETE FROM reactions WHERE target_sender_aci = $1 AND target_sent_at = $2")
+        .bind(sender_aci)
+        .bind(sent_at)
+        .execute(&mut *conn)
+        .await?;
+    for table in ["message_revisions", "embeddings"] {
+        sqlx::query(&format!(
+            "DELETE FROM {table} WHERE sender_aci = $1 AND sent_at = $2"
+        ))
+        .bind(sender_aci)
+        .bind(sent_at)
+        .execute(&mut *conn)
+        .await?;
+    }
+    let removed: Vec<(String, String)> = sqlx::query_as(
+        "DELETE FROM attachments WHERE sender_aci = $1 AND sent_at = $2 RETURNING sha256, path",
+    )
+    .bind(sender_aci)
+    .bind(sent_at)
+    .fetch_all(&mut *conn)
+    .await?;
+    orphaned_files(conn, removed).await
+}
+
+/// The paths among `removed` attachment records whose contents no remaining record
+/// refers to; files are shared by every message that sent the same contents.
+async fn orphaned_files(
+    conn: &mut PgConnection,
+    removed: Vec<(String, String)>,
+) -> Result<Vec<String>, sqlx::Error> {
+    if removed.is_empty() {
+        return Ok(vec![]);
+    }
+    let (digests, paths): (Vec<String>, Vec<String>) = removed.into_iter().unzip();
+    sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT g.path FROM unnest($1::text[], $2::text[]) AS g (sha256, path)
+        WHERE NOT EXISTS (SELECT 1 FROM attachments a WHERE a.sha256 = g.sha256)
+        "#,
+    )
+    .bind(&digests)
+    .bind(&paths)
+    .fetch_all(&mut *conn)
+    .await
+}
+
+/// Unlinks attachment files under `dir`, given by their paths relative to it.
+pub async fn remove_files(dir: &Path, paths: &[String]) {
+    for path in paths {
+        match tokio::fs::remove_file(dir.join(path)).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => warn!(path, %error, "failed to remove attachment file"),
+        }
+    }
+}
+
+/// Remembers the disappearing-message timer of a thread; `expire_timer` 0 turns it off.
+pub async fn record_expire_timer(
+    pool: &Pool<Postgres>,
+    thread_id: &str,
+    expire_timer: u32,
+    sent_at: i64,
+) -> Result<(), sqlx::Error> {
+    // Messages may arrive out of order, the newest setting wins
+    sqlx::query(
+        r#"
+        INSERT INTO thread_expiry (thread_id, expire_timer, updated_at) VALUES ($1, $2, $3)
+        ON CONFLICT (thread_id) DO UPDATE
+            SET expire_timer = EXCLUDED.expire_timer, updated_at = EXCLUDED.updated_at
+            WHERE thread_expiry.updated_at < EXCLUDED.updated_at
+        "#,
+    )
+    .bind(thread_id)
+    .bind(expire_timer as i32)
+    .bind(sent_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gives the just stored messages `sender_acis[i]`, `sent_ats[i]` that came without a
+/// timer, such as imported history, the expiry of their thread's recorded timer, if it
+/// was on by the time they were sent. The reaper then removes them like any other.
+pub async fn apply_thread_timers(
+    conn: &mut PgConnection,
+    sender_acis: &[String],
+    sent_ats: &[i64],
+) -> Result<(), sqlx::Error> {
+    if sender_acis.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        r#"
+        UPDATE embeddings e SET expires_at = e.sent_at + t.expire_timer::bigint * 1000
+        FROM thread_expiry t
+        WHERE (e.sender_aci, e.sent_at) IN (SELECT * FROM unnest($1::text[], $2::bigint[]))
+            AND e.expires_at IS NULL AND e.thread_id = t.thread_id
+            AND t.expire_timer > 0 AND e.sent_at >= t.updated_at
+        "#,
+    )
+    .bind(sender_acis)
+    .bind(sent_ats)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Deletes every message whose timer has run out, with the attachment files no other
+/// message shares under `attachments_dir`, and returns how many rows went.
+pub async fn reap_expired(
+    pool: &Pool<Postgres>,
+    attachments_dir: &Path,
+) -> Result<u64, sqlx::Error> {
+    let now = now_millis();
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+        DELETE FROM conversation_windows WHERE id IN (
+            SELECT m.window_id FROM conversation_window_messages m
+            JOIN embeddings e ON e.id = m.embedding_id
+            WHERE e.expires_at <= $1
+        )
+        "#,
+    )
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        DELETE FROM message_revisions r USING embeddings e
+        WHERE e.sender_aci = r.sender_aci AND e.sent_at = r.sent_at AND e.expires_at <= $1
+        "#,
+    )
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        DELETE FROM reactions r USING embeddings e
+        WHERE e.sender_aci = r.target_sender_aci AND e.sent_at = r.target_sent_at
+            AND e.expires_at <= $1
+        "#,
+    )
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    let removed: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        DELETE FROM attachments a USING embeddings e
+        WHERE e.sender_aci = a.sender_aci AND e.sent_at = a.sent_at AND e.expires_at <= $1
+        RETURNING a.sha256, a.path
+        "#,
+    )
+    .bind(now)
+    .fetch_all(&mut *tx)
+    .await?;
+    let orphans = orphaned_files(&mut tx, removed).await?;
+    let reaped = sqlx::query("DELETE FROM embeddings WHERE expires_at <= $1")
+        .bind(now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    remove_files(attachments_dir, &orphans).await;
+    Ok(reaped)
+}
+
+/// Starts the task removing disappeared messages and their attachment files, unless
+/// under legal hold.
+pub fn spawn_reaper(
+    pool: Pool<Postgres>,
+    config: &RetentionConfig,
+    attachments_dir: PathBuf,
+) -> Option<JoinHandle<()>> {
+    if config.legal_hold {
+        return None;
+    }
+
+    let period = Duration::from_secs(config.reap_interval_secs.max(1));
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match reap_expired(&pool, &attachments_dir).await {
+                Ok(0) => {}
+                Ok(reaped) => info!(reaped, "removed disappeared messages"),
+                Err(error) => error!(%error, "failed to remove disappeared messages"),
+            }
+        }
+    }))
+}
@@ -0,0 +1,432 @@
+use anyhow::{anyhow, Context as _};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use pgvector::Vector;
+use sqlx::FromRow;
+
+use crate::rag::attachments::describe_source;
+use crate::rag::encryption::open_body;
+use crate::rag::index::QueryTuning;
+use crate::rag::reactions::normalize_emoji;
+use crate::rag::vector_db::VectorDb;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Granularity {
+    /// Single messages, or chunks of long ones.
+    #[default]
+    Message,
+    /// Conversation windows built by `build-windows`.
+    Window,
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchFilter {
+    pub limit: i64,
+    pub contact: Option<String>,
+    pub group: Option<String>,
+    pub direction: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Pins the search to exactly one conversation: a contact uuid or hex group master key.
+    pub thread: Option<String>,
+    /// Returns one result per message, with the chunks of long messages joined again.
+    pub per_message: bool,
+    /// Also searches the text messages had before they were edited.
+    pub include_revisions: bool,
+    /// Only messages that got this reaction.
+    pub reaction: Option<String>,
+    /// Only messages we reacted to.
+    pub reacted_by_me: bool,
+    /// How much reactions raise a result above its similarity: the score is
+    /// `similarity + weight * ln(1 + reactions)`.
+    pub reaction_weight: f64,
+    pub granularity: Granularity,
+    pub tuning: QueryTuning,
+}
+
+impl Default for SearchFilter {
+    fn default() -> Self {
+        SearchFilter {
+            limit: 10,
+            contact: None,
+            group: None,
+            direction: None,
+            since: None,
+            until: None,
+            thread: None,
+            per_message: false,
+            include_revisions: false,
+            reaction: None,
+            reacted_by_me: false,
+            reaction_weight: 0.0,
+            granularity: Granularity::Message,
+            tuning: QueryTuning::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct SearchResult {
+    pub id: i64,
+    pub body: Option<String>,
+    pub direction: Option<String>,
+    pub contact: Option<String>,
+    pub group_name: Option<String>,
+    pub sender_aci: Option<String>,
+    pub thread_id: Option<String>,
+    pub sent_at: Option<i64>,
+    /// Identity of the message this one replies to.
+    pub reply_to_sender_aci: Option<String>,
+    pub reply_to_sent_at: Option<i64>,
+    pub chunk_index: i32,
+    pub chunk_count: i32,
+    /// Attachment the text was extracted from, `None` for the message body.
+    pub attachment: Option<String>,
+    /// How the text was obtained, e.g. `message`, `pdf` or `ocr`.
+    pub source: String,
+    /// Text the message had before a later edit.
+    pub superseded: bool,
+    /// Reactions the message currently has.
+    pub reactions: i64,
+    /// When the message was sent, or stored for rows that predate `sent_at`.
+    pub timestamp: DateTime<Utc>,
+    pub similarity: f64,
+}
+
+impl SearchResult {
+    /// Whether this is a conversation window, whose id is not a message id.
+    pub fn is_window(&self) -> bool {
+        self.source == "window"
+    }
+
+    pub fn to_line(&self) -> String {
+        let chat = match (&self.group_name, &self.contact) {
+            (Some(group), Some(contact)) => format!("{contact} in {group}"),
+            (Some(group), None) => group.clone(),
+            (None, Some(contact)) => contact.clone(),
+            (None, None) => String::new(),
+        };
+        let mut notes = String::new();
+        if self.reactions > 0 {
+            notes.push_str(&format!(" ({} reactions)", self.reactions));
+        }
+        if self.superseded {
+            notes.push_str(" (edited since)");
+        }
+        if let Some(attachment) = &self.attachment {
+            notes.push_str(&format!(" ({})", describe_source(&self.source, attachment)));
+        }
+        // Windows are numbered apart, so `thread` is never given one
+        let prefix = if self.is_window() { "w#" } else { "#" };
+        format!(
+            "[{:.3}] {}{} {} {} {}: {}{}",
+            self.similarity,
+            prefix,
+            self.id,
+            self.timestamp.format("%Y-%m-%d %H:%M"),
+            self.direction.clone().unwrap_or_default(),
+            chat,
+            self.body.clone().unwrap_or_default(),
+            notes,
+        )
+    }
+}
+
+/// Embeds `query` with the ingest model and returns the closest stored messages.
+pub async fn search_messages(
+    vector_db: &VectorDb,
+    query: &str,
+    filter: &SearchFilter,
+) -> anyhow::Result<Vec<SearchResult>> {
+    let embedding = vector_db
+        .embedder
+        .embed(query)
+        .await
+        .context("failed to embed query")?;
+
+    Ok(search_by_embedding(vector_db, Vector::from(embedding), filter).await?)
+}
+
+// Current rows plus earlier revisions, with the metadata of the message they belong to
+const WITH_REVISIONS: &str = r#"
+    (
+        SELECT id, body, direction, contact, group_name, sender_aci, thread_id, sent_at,
+            reply_to_sender_aci, reply_to_sent_at, chunk_index, chunk_count, attachment,
+            source, false AS superseded, created_at, embedding
+        FROM embeddings
+        UNION ALL
+        -- Revisions answer to the current message's id, which `thread` and `ask` look up
+        SELECT e.id, r.body, e.direction, e.contact, e.group_name, r.sender_aci, e.thread_id,
+            r.sent_at, e.reply_to_sender_aci, e.reply_to_sent_at, r.chunk_index, 1, '',
+            'message', true, r.created_at, r.embedding
+        FROM message_revisions r
+        JOIN embeddings e
+            ON e.sender_aci = r.sender_aci AND e.sent_at = r.sent_at
+                AND e.attachment = '' AND e.chunk_index = 0
+    ) AS embeddings
+"#;
+
+// Chunks fetched per wanted result when regrouping, since several may share a message
+const CHUNKS_PER_RESULT: i64 = 4;
+
+pub async fn search_by_embedding(
+    vector_db: &VectorDb,
+    embedding: Vector,
+    filter: &SearchFilter,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    if filter.granularity == Granularity::Window {
+        return search_windows(vector_db, embedding, filter).await;
+    }
+
+    let rerank = filter.reaction_weight != 0.0;
+    let limit = match filter.per_message || rerank {
+        true => filter.limit * CHUNKS_PER_RESULT,
+        false => filter.limit,
+    };
+
+    let mut tx = vector_db.pool.begin().await?;
+    filter.tuning.apply(&mut tx).await?;
+
+    // Kept to the plain table by default so the similarity index can be used
+    let table = match filter.include_revisions {
+        true => WITH_REVISIONS,
+        false => "embeddings",
+    };
+    let mut results: Vec<SearchResult> = sqlx::query_as(&format!(
+        r#"
+        SELECT id, body, direction, contact, group_name, sender_aci, thread_id, sent_at,
+            reply_to_sender_aci, reply_to_sent_at, chunk_index, chunk_count,
+            NULLIF(attachment, '') AS attachment, source, {superseded} AS superseded,
+            (SELECT count(*) FROM reactions r
+                WHERE r.target_sender_aci = embeddings.sender_aci
+                    AND r.target_sent_at = embeddings.sent_at AND NOT r.removed) AS reactions,
+            COALESCE(to_timestamp(sent_at / 1000.0), created_at) AS timestamp,
+            1 - (embedding <=> $1) AS similarity
+        FROM {table}
+        WHERE embedding IS NOT NULL
+            AND ($2::text IS NULL OR strpos(lower(contact), lower($2)) > 0)
+            AND ($3::text IS NULL OR strpos(lower(group_name), lower($3)) > 0)
+            AND ($4::text IS NULL OR direction = $4)
+            AND ($5::timestamptz IS NULL
+                OR COALESCE(to_timestamp(sent_at / 1000.0), created_at) >= $5)
+            AND ($6::timestamptz IS NULL
+                OR COALESCE(to_timestamp(sent_at / 1000.0), created_at) < $6)
+            AND ($8::text IS NULL OR thread_id = $8)
+            AND (($9::text IS NULL AND NOT $10) OR EXISTS (
+                SELECT 1 FROM reactions r
+                WHERE r.target_sender_aci = embeddings.sender_aci
+                    AND r.target_sent_at = embeddings.sent_at AND NOT r.removed
+                    AND ($9::text IS NULL OR r.emoji = $9)
+                    AND (NOT $10 OR r.from_me)
+            ))
+        ORDER BY embedding <=> $1
+        LIMIT $7
+        "#,
+        superseded = match filter.include_revisions {
+            true => "superseded",
+            false => "false",
+        },
+    ))
+    .bind(embedding)
+    .bind(&filter.contact)
+    .bind(&filter.group)
+    .bind(&filter.direction)
+    .bind(filter.since)
+    .bind(filter.until)
+    .bind(limit)
+    .bind(&filter.thread)
+    .bind(filter.reaction.as_deref().map(normalize_emoji))
+    .bind(filter.reacted_by_me)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    for result in &mut results {
+        result.body = open_body(vector_db.cipher.as_ref(), result.body.take());
+    }
+
+    if rerank {
+        let score = |result: &SearchResult| {
+            result.similarity + filter.reaction_weight * (result.reactions as f64).ln_1p()
+        };
+        results.sort_by(|a, b| score(b).total_cmp(&score(a)));
+    }
+    if !filter.per_message {
+        results.truncate(filter.limit as usize);
+        return Ok(results);
+    }
+    group_by_message(vector_db, results, filter.limit as usize).await
+}
+
+/// Searches conversation windows; a window matches the time range if any of it
+/// falls inside, and `direction` does not apply.
+async fn search_windows(
+    vector_db: &VectorDb,
+    embedding: Vector,
+    filter: &SearchFilter,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    let mut tx = vector_db.pool.begin().await?;
+    filter.tuning.apply(&mut tx).await?;
+
+    let mut results: Vec<SearchResult> = sqlx::query_as(
+        r#"
+        SELECT id, body, NULL::text AS direction, contact, group_name,
+            NULL::text AS sender_aci, thread_id, started_at AS sent_at,
+            NULL::text AS reply_to_sender_aci, NULL::bigint AS reply_to_sent_at,
+            0 AS chunk_index, 1 AS chunk_count, NULL::text AS attachment, 'window' AS source,
+            false AS superseded,
+            0::bigint AS reactions,
+            to_timestamp(started_at / 1000.0) AS timestamp,
+            1 - (embedding <=> $1) AS similarity
+        FROM conversation_windows
+        WHERE embedding IS NOT NULL
+            AND ($2::text IS NULL OR strpos(lower(contact), lower($2)) > 0)
+            AND ($3::text IS NULL OR strpos(lower(group_name), lower($3)) > 0)
+            AND ($4::timestamptz IS NULL OR to_timestamp(ended_at / 1000.0) >= $4)
+            AND ($5::timestamptz IS NULL OR to_timestamp(started_at / 1000.0) < $5)
+            AND ($7::text IS NULL OR thread_id = $7)
+        ORDER BY embedding <=> $1
+        LIMIT $6
+        "#,
+    )
+    .bind(embedding)
+    .bind(&filter.contact)
+    .bind(&filter.group)
+    .bind(filter.since)
+    .bind(filter.until)
+    .bind(filter.limit)
+    .bind(&filter.thread)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    for result in &mut results {
+        result.body = open_body(vector_db.cipher.as_ref(), result.body.take());
+    }
+    Ok(results)
+}
+
+/// Keeps the best chunk of each message and replaces its body with the whole message.
+async fn group_by_message(
+    vector_db: &VectorDb,
+    results: Vec<SearchResult>,
+    limit: usize,
+) -> Result<Vec<SearchResult>, sqlx::Error> {
+    let mut grouped: Vec<SearchResult> = vec![];
+    for result in results {
+        // Results arrive best first, so the first chunk seen of a message wins
+        let seen = grouped.iter().any(|other| {
+            result.sender_aci.is_some()
+                && result.sent_at.is_some()
+                && other.sender_aci == result.sender_aci
+                && other.sent_at == result.sent_at
+                && other.attachment == result.attachment
+                && other.source == result.source
+        });
+        if !seen {
+            grouped.push(result);
+        }
+        if grouped.len() == limit {
+            break;
+        }
+    }
+
+    for result in grouped.iter_mut().filter(|result| result.chunk_count > 1) {
+        if let (Some(sender_aci), Some(sent_at)) = (&result.sender_aci, result.sent_at) {
+            let attachment = result.attachment.as_deref().unwrap_or_default();
+            result.body = Some(
+                message_body(vector_db, sender_aci, sent_at, attachment, &result.source).await?,
+            );
+        }
+    }
+    Ok(grouped)
+}
+
+/// Joins the chunks of a message back into its full body, dropping the overlaps.
+///
+/// `attachment` and `source` select text extracted from an attachment instead; the
+/// body itself is attachment `""` with source `message`.
+pub async fn message_body(
+    vector_db: &VectorDb,
+    sender_aci: &str,
+    sent_at: i64,
+    attachment: &str,
+    source: &str,
+) -> Result<String, sqlx::Error> {
+    let chunks: Vec<(Option<String>, Option<i32>)> = sqlx::query_as(
+        r#"
+        SELECT body, char_start FROM embeddings
+        WHERE sender_aci = $1 AND sent_at = $2 AND attachment = $3 AND source = $4
+        ORDER BY chunk_index
+        "#,
+    )
+    .bind(sender_aci)
+    .bind(sent_at)
+    .bind(attachment)
+    .bind(source)
+    .fetch_all(&vector_db.pool)
+    .await?;
+
+    Ok(join_chunks(chunks.into_iter().map(|(body, char_start)| {
+        (open_body(vector_db.cipher.as_ref(), body), char_start)
+    })))
+}
+
+/// Joins chunk texts with their `char_start`, in chunk order, dropping the overlaps.
+pub fn join_chunks(chunks: impl IntoIterator<Item = (Option<String>, Option<i32>)>) -> String {
+    let mut body = String::new();
+    let mut body_chars: usize = 0;
+    for (chunk, char_start) in chunks {
+        let chunk = chunk.unwrap_or_default();
+        let skip = char_start.map_or(0, |start| body_chars.saturating_sub(start as usize));
+        body.extend(chunk.chars().skip(skip));
+        body_chars = body.chars().count();
+    }
+    body
+}
+
+/// Accepts either an RFC 3339 timestamp or a plain `YYYY-MM-DD` date (midnight UTC).
+pub fn parse_datetime(value: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| anyhow!("expected YYYY-MM-DD or an RFC 3339 timestamp"))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(text: &str, char_start: i32) -> (Option<String>, Option<i32>) {
+        (Some(String::from(text)), Some(char_start))
+    }
+
+    #[test]
+    fn overlapping_chunks_are_joined_once() {
+        let chunks = [chunk("hello wo", 0), chunk("world", 6)];
+        assert_eq!(join_chunks(chunks), "hello world");
+    }
+
+    #[test]
+    fn overlaps_are_counted_in_characters() {
+        let chunks = [chunk("héllo w", 0), chunk("wörld", 6)];
+        assert_eq!(join_chunks(chunks), "héllo wörld");
+    }
+
+    #[test]
+    fn chunks_without_offsets_are_appended() {
+        let chunks = [
+            (Some(String::from("one ")), None),
+            (Some(String::from("two")), None),
+        ];
+        assert_eq!(join_chunks(chunks), "one two");
+    }
+
+    #[test]
+    fn a_single_chunk_is_the_body() {
+        assert_eq!(join_chunks([chunk("short", 0)]), "short");
+        assert_eq!(join_chunks([(None, Some(0))]), "");
+    }
+}
@@ -1,12 +1,20 @@
+use anyhow::bail;
 use dotenv::dotenv;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use pgvector::Vector;
+use sqlx::{postgres::PgPoolOptions, PgConnection, Pool, Postgres, QueryBuilder};
 use std::env;
+use tracing::info;
 
 use crate::rag::dataframes::SignalMessageWithVector;
+use crate::rag::embedder::EmbedderConfig;
+use crate::rag::migrations::run_migrations;
+use crate::rag::retention::apply_thread_timers;
 
 use super::dataframes::SignalMessageWithEmbedding;
 
-pub async fn setup_database() -> Result<Pool<Postgres>, sqlx::Error> {
+/// Connects and applies pending migrations. Vectors keep the dimension they are stored
+/// with; only a new database asks the configured embedder for one.
+pub async fn setup_database(embedder: &EmbedderConfig) -> anyhow::Result<Pool<Postgres>> {
     dotenv().ok();
 
     // Replace with your actual connection string
@@ -18,69 +26,155 @@ pub async fn setup_database() -> Result<Pool<Postgres>, sqlx::Error> {
         .connect(connection_string.as_str())
         .await?;
 
-    // Install pgvector extension
-    sqlx::query("CREATE EXTENSION IF NOT EXISTS vector;")
-        .execute(&pool)
-        .await?;
+    let dimension = match stored_dimension(&pool).await? {
+        Some(dimension) => dimension as usize,
+        None => embedder.build().dimension().await?,
+    };
 
-    // Install pgvectorscale extension
-    sqlx::query("CREATE EXTENSION IF NOT EXISTS vectorscale CASCADE;")
-        .execute(&pool)
-        .await?;
+    // Create or upgrade the schema
+    for version in run_migrations(&pool, dimension).await? {
+        info!(version, "applied migration");
+    }
 
-    // Create table to store embeddings and metadata
-    match sqlx::query(
+    Ok(pool)
+}
+
+/// Dimension of the stored vectors, `None` before the `embeddings` table is created.
+pub async fn stored_dimension(pool: &Pool<Postgres>) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar(
         r#"
-        CREATE TABLE IF NOT EXISTS embeddings (
-            id bigserial primary key,
-            body text,
-            direction text,
-            contact text,
-            group_name text,
-            attachments text,
-            tokens integer,
-            embedding VECTOR(768),
-            created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
-        );
+        SELECT atttypmod FROM pg_attribute
+        WHERE attrelid = to_regclass('embeddings') AND attname = 'embedding'
         "#,
     )
-    .execute(&pool)
-    .await {
-        Ok(x) => x,
-        Err(err) => panic!("{:?}",err),
-    };
-
-    Ok(pool)
+    .fetch_optional(pool)
+    .await
 }
 
+// Postgres accepts at most 65535 bind parameters per statement
+const INSERT_BATCH_ROWS: usize = 1000;
+
 pub async fn insert_embeddings_into_db(
     pool: &Pool<Postgres>,
     msg_to_encode: Vec<SignalMessageWithEmbedding>,
-) -> Result<(), sqlx::Error> {
-    for msg in msg_to_encode {
-        match sqlx::query(
-            r#"
-            INSERT INTO embeddings (body,direction,contact,group_name,attachments,tokens,embedding)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            "#,
-        )
-        .bind(&msg.body)
-        .bind(&msg.direction)
-        .bind(&msg.contact)
-        .bind(&msg.group_name)
-        .bind(&msg.attachments)
-        .bind(&msg.tokens)
-        .bind(&msg.embedding)
-        .execute(pool)
-        .await {
-            Ok(_) => {},
-            Err(err) => println!("{}",err),
-        }
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    insert_embeddings(&mut tx, &msg_to_encode).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Inserts rows on `conn`, so callers can make it part of a transaction. Fails if their
+/// vectors are not from the active model; the caller's transaction must then be dropped.
+pub async fn insert_embeddings(
+    conn: &mut PgConnection,
+    msg_to_encode: &[SignalMessageWithEmbedding],
+) -> anyhow::Result<()> {
+    for rows in msg_to_encode.chunks(INSERT_BATCH_ROWS) {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO embeddings (body,direction,contact,group_name,attachments,tokens,embedding,\
+                embedding_model,sent_at,sender_aci,thread_id,server_guid,kind,chunk_index,chunk_count,char_start,char_end,\
+                expires_at,reply_to_sender_aci,reply_to_sent_at,source,attachment,language) ",
+        );
+        query.push_values(rows, |mut row, msg| {
+            row.push_bind(&msg.body)
+                .push_bind(&msg.direction)
+                .push_bind(&msg.contact)
+                .push_bind(&msg.group_name)
+                .push_bind(&msg.attachments)
+                .push_bind(msg.tokens)
+                .push_bind(
+                    Some(msg.embedding.clone())
+                        .filter(|embedding| !embedding.is_empty())
+                        .map(Vector::from),
+                )
+                .push_bind(&msg.embedding_model)
+                .push_bind(msg.sent_at)
+                .push_bind(&msg.sender_aci)
+                .push_bind(&msg.thread_id)
+                .push_bind(&msg.server_guid)
+                .push_bind(&msg.kind)
+                .push_bind(msg.chunk_index)
+                .push_bind(msg.chunk_count)
+                .push_bind(msg.char_start)
+                .push_bind(msg.char_end)
+                .push_bind(msg.expires_at)
+                .push_bind(&msg.reply_to_sender_aci)
+                .push_bind(msg.reply_to_sent_at)
+                .push_bind(&msg.source)
+                .push_bind(&msg.attachment)
+                .push_bind(&msg.language);
+        });
+        query.push(" ON CONFLICT (sender_aci, sent_at, attachment, source, chunk_index) DO NOTHING");
+
+        query.build().execute(&mut *conn).await?;
     }
+    let (sender_acis, sent_ats): (Vec<String>, Vec<i64>) = msg_to_encode
+        .iter()
+        .filter(|msg| msg.expires_at.is_none())
+        .filter_map(|msg| Some((msg.sender_aci.clone()?, msg.sent_at?)))
+        .unzip();
+    apply_thread_timers(conn, &sender_acis, &sent_ats).await?;
 
+    let mut models: Vec<&str> = msg_to_encode
+        .iter()
+        .filter_map(|msg| msg.embedding_model.as_deref())
+        .collect();
+    models.sort_unstable();
+    models.dedup();
+    for model in models {
+        ensure_active_model(conn, model).await?;
+    }
     Ok(())
 }
 
+/// Fails unless `model` is the active embedding model, so a process started before a
+/// `reembed` switch cannot put vectors of the old model in the swapped column.
+///
+/// Run on the writer's transaction after its insert: the insert's lock either holds the
+/// switch off until the transaction ends, or waits for the switch, which is then seen.
+pub async fn ensure_active_model(conn: &mut PgConnection, model: &str) -> anyhow::Result<()> {
+    let active: Option<String> =
+        sqlx::query_scalar("SELECT model FROM embedding_models WHERE active")
+            .fetch_optional(&mut *conn)
+            .await?;
+    match active {
+        Some(active) if active != model => bail!(
+            "`reembed` switched the embedding model from {model} to {active}; restart to use it"
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub async fn message_exists(
+    pool: &Pool<Postgres>,
+    sender_aci: &str,
+    sent_at: i64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM embeddings WHERE sender_aci = $1 AND sent_at = $2)",
+    )
+    .bind(sender_aci)
+    .bind(sent_at)
+    .fetch_one(pool)
+    .await
+}
+
+/// Whether the message was deleted for everyone, even if it was never stored.
+pub async fn message_deleted(
+    pool: &Pool<Postgres>,
+    sender_aci: &str,
+    sent_at: i64,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM deleted_messages WHERE sender_aci = $1 AND sent_at = $2)",
+    )
+    .bind(sender_aci)
+    .bind(sent_at)
+    .fetch_one(pool)
+    .await
+}
+
 pub async fn get_all_embeddings_from_db(
     pool: &Pool<Postgres>,
 ) -> Result<Vec<SignalMessageWithVector>, sqlx::Error> {
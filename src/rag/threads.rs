@@ -0,0 +1,148 @@
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use sqlx::FromRow;
+
+use crate::rag::encryption::open_body;
+use crate::rag::vector_db::VectorDb;
+
+// Guards the recursive queries against reply loops
+const MAX_DEPTH: i32 = 100;
+
+const COLUMNS: &str = "id, body, direction, contact, group_name, sender_aci, sent_at, \
+    reply_to_sender_aci, reply_to_sent_at, \
+    COALESCE(to_timestamp(sent_at / 1000.0), created_at) AS timestamp";
+
+/// Reads a message id as search and ask print it, with or without its `#`. Window ids,
+/// printed as `w#`, are refused: they number `conversation_windows`, not messages.
+pub fn parse_message_id(value: &str) -> anyhow::Result<i64> {
+    if value.starts_with('w') {
+        bail!("{value} is a conversation window; give the id of a message");
+    }
+    Ok(value.strip_prefix('#').unwrap_or(value).parse()?)
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct ThreadMessage {
+    pub id: i64,
+    pub body: Option<String>,
+    pub direction: Option<String>,
+    pub contact: Option<String>,
+    pub group_name: Option<String>,
+    pub sender_aci: Option<String>,
+    pub sent_at: Option<i64>,
+    pub reply_to_sender_aci: Option<String>,
+    pub reply_to_sent_at: Option<i64>,
+    pub timestamp: DateTime<Utc>,
+    /// Replies below the message the chain was requested for are positive,
+    /// the messages it replies to negative.
+    pub depth: i32,
+}
+
+impl ThreadMessage {
+    pub fn to_line(&self, indent: usize) -> String {
+        let speaker = match self.direction.as_deref() {
+            Some("to") => String::from("me"),
+            _ => self.contact.clone().unwrap_or_default(),
+        };
+        format!(
+            "{}#{} {} {}: {}",
+            "  ".repeat(indent),
+            self.id,
+            self.timestamp.format("%Y-%m-%d %H:%M"),
+            speaker,
+            self.body.clone().unwrap_or_default(),
+        )
+    }
+}
+
+/// The first chunk of the message identified by author and sent timestamp in chat
+/// `thread_id`. Quotes are sender-controlled, so they never resolve across chats.
+pub async fn find_message(
+    vector_db: &VectorDb,
+    thread_id: &str,
+    sender_aci: &str,
+    sent_at: i64,
+) -> Result<Option<ThreadMessage>, sqlx::Error> {
+    let message: Option<ThreadMessage> = sqlx::query_as(&format!(
+        "SELECT {COLUMNS}, 0 AS depth FROM embeddings \
+            WHERE sender_aci = $1 AND sent_at = $2 AND thread_id = $3 \
+            AND attachment = '' AND chunk_index = 0"
+    ))
+    .bind(sender_aci)
+    .bind(sent_at)
+    .bind(thread_id)
+    .fetch_optional(&vector_db.pool)
+    .await?;
+    Ok(message.map(|message| open_message(vector_db, message)))
+}
+
+/// The messages row `id` replies to, oldest first, then the message itself and
+/// every reply to it, each reply after its parent. Only its own chat is followed.
+pub async fn reply_chain(vector_db: &VectorDb, id: i64) -> Result<Vec<ThreadMessage>, sqlx::Error> {
+    let mut ancestors: Vec<ThreadMessage> = sqlx::query_as(&format!(
+        r#"
+        WITH RECURSIVE root AS (
+            SELECT thread_id FROM embeddings WHERE id = $1
+        ),
+        up AS (
+            SELECT {COLUMNS}, 0 AS depth FROM embeddings WHERE id = $1
+            UNION ALL
+            SELECT p.id, p.body, p.direction, p.contact, p.group_name, p.sender_aci, p.sent_at,
+                p.reply_to_sender_aci, p.reply_to_sent_at,
+                COALESCE(to_timestamp(p.sent_at / 1000.0), p.created_at),
+                up.depth - 1
+            FROM embeddings p
+            JOIN up ON p.sender_aci = up.reply_to_sender_aci
+                AND p.sent_at = up.reply_to_sent_at AND p.attachment = '' AND p.chunk_index = 0
+                AND p.thread_id = (SELECT thread_id FROM root)
+            WHERE up.depth > -$2
+        )
+        SELECT * FROM up WHERE depth < 0 ORDER BY depth
+        "#
+    ))
+    .bind(id)
+    .bind(MAX_DEPTH)
+    .fetch_all(&vector_db.pool)
+    .await?;
+
+    let replies: Vec<ThreadMessage> = sqlx::query_as(&format!(
+        r#"
+        WITH RECURSIVE root AS (
+            SELECT thread_id FROM embeddings WHERE id = $1
+        ),
+        down AS (
+            SELECT {COLUMNS}, 0 AS depth, ARRAY[id] AS path FROM embeddings WHERE id = $1
+            UNION ALL
+            SELECT c.id, c.body, c.direction, c.contact, c.group_name, c.sender_aci, c.sent_at,
+                c.reply_to_sender_aci, c.reply_to_sent_at,
+                COALESCE(to_timestamp(c.sent_at / 1000.0), c.created_at),
+                down.depth + 1, down.path || c.id
+            FROM embeddings c
+            JOIN down ON c.reply_to_sender_aci = down.sender_aci
+                AND c.reply_to_sent_at = down.sent_at AND c.attachment = '' AND c.chunk_index = 0
+                AND c.thread_id = (SELECT thread_id FROM root)
+            WHERE down.depth < $2
+        )
+        SELECT id, body, direction, contact, group_name, sender_aci, sent_at,
+            reply_to_sender_aci, reply_to_sent_at, timestamp, depth
+        FROM down ORDER BY path
+        "#
+    ))
+    .bind(id)
+    .bind(MAX_DEPTH)
+    .fetch_all(&vector_db.pool)
+    .await?;
+
+    ancestors.extend(replies);
+    Ok(ancestors
+        .into_iter()
+        .map(|message| open_message(vector_db, message))
+        .collect())
+}
+
+fn open_message(vector_db: &VectorDb, message: ThreadMessage) -> ThreadMessage {
+    ThreadMessage {
+        body: open_body(vector_db.cipher.as_ref(), message.body.clone()),
+        ..message
+    }
+}
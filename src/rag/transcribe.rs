@@ -0,0 +1,185 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{bail, Context as _};
+use serde_json::Value;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::rag::attachments::AttachmentConfig;
+use crate::rag::encryption::Cipher;
+use crate::signal::process_incoming_message::{ProcessedMessage, VOICE_NOTE_BODY};
+
+/// What whisper.cpp heard in a voice note.
+#[derive(Clone, Debug)]
+pub struct Transcript {
+    pub text: String,
+    /// Language code such as `en`, detected unless one was configured.
+    pub language: String,
+}
+
+/// Replaces the placeholder body of the voice notes in `messages` with their transcript
+/// when a whisper model is configured. Voice notes that cannot be transcribed keep it.
+pub async fn transcribe_voice_notes(
+    config: &AttachmentConfig,
+    cipher: Option<&Cipher>,
+    messages: &mut [ProcessedMessage],
+) {
+    let Some(model) = &config.whisper_model else {
+        return;
+    };
+    for message in messages.iter_mut() {
+        let Some(file_name) = &message.voice_note else {
+            continue;
+        };
+        let path = config.dir.join(file_name);
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            debug!(file_name, "voice note not available");
+            continue;
+        }
+        match transcribe(config, cipher, model, &path).await {
+            Ok(transcript) if !transcript.text.is_empty() => {
+                info!(
+                    file_name,
+                    language = transcript.language,
+                    "transcribed voice note"
+                );
+                message.body = Some(match message.body.as_deref() {
+                    None | Some(VOICE_NOTE_BODY) => transcript.text,
+                    Some(body) => format!("{body}\n\n{}", transcript.text),
+                });
+                message.language = Some(transcript.language);
+            }
+            Ok(_) => debug!(file_name, "no speech in voice note"),
+            Err(error) => warn!(file_name, %error, "failed to transcribe voice note"),
+        }
+    }
+}
+
+/// Converts `audio` to the 16 kHz mono WAV whisper.cpp reads and transcribes it on the CPU.
+///
+/// The WAV, the transcript and, for a sealed voice note, the opened audio are all plaintext,
+/// so they go in a private directory (mode 0700, files 0600) removed on every return. The
+/// audio cannot be piped instead: ffmpeg needs to seek in MP4 containers such as `.m4a`.
+pub async fn transcribe(
+    config: &AttachmentConfig,
+    cipher: Option<&Cipher>,
+    model: &Path,
+    audio: &Path,
+) -> anyhow::Result<Transcript> {
+    let dir = tempfile::Builder::new()
+        .prefix("signal-voice-")
+        .tempdir()
+        .context("failed to create a directory for transcription")?;
+    let wav = dir.path().join("audio.wav");
+    let base = dir.path().join("transcript");
+    let json = dir.path().join("transcript.json");
+
+    let opened = match cipher {
+        Some(cipher) => {
+            let data = cipher.open(&tokio::fs::read(audio).await?)?;
+            let mut file = tempfile::NamedTempFile::new_in(dir.path())?;
+            file.write_all(&data)?;
+            Some(file)
+        }
+        None => None,
+    };
+    let input = opened.as_ref().map_or(audio, |file| file.path());
+
+    run(Command::new(&config.ffmpeg)
+        .args(["-nostdin", "-loglevel", "error", "-y", "-i"])
+        .arg(input)
+        .args(["-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"])
+        .arg(&wav))
+    .await
+    .with_context(|| format!("failed to convert with {}", config.ffmpeg))?;
+    run(Command::new(&config.whisper)
+        .arg("-m")
+        .arg(model)
+        .arg("-f")
+        .arg(&wav)
+        .args(["-l", &config.transcribe_language])
+        .args(["-t", &config.whisper_threads.to_string()])
+        // CPU only, no progress output, results as JSON next to `base`
+        .args(["-ng", "-np", "-oj", "-of"])
+        .arg(&base))
+    .await
+    .with_context(|| format!("failed to transcribe with {}", config.whisper))?;
+    let output = tokio::fs::read_to_string(&json).await?;
+    parse_whisper_json(&output)
+}
+
+async fn run(command: &mut Command) -> anyhow::Result<()> {
+    let output = command.output().await?;
+    if !output.status.success() {
+        bail!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Reads the output of whisper.cpp's `--output-json`.
+fn parse_whisper_json(output: &str) -> anyhow::Result<Transcript> {
+    let value: Value = serde_json::from_str(output)?;
+    let language = value["result"]["language"]
+        .as_str()
+        .context("no language in whisper output")?
+        .to_string();
+    let segments = value["transcription"]
+        .as_array()
+        .context("no transcription in whisper output")?;
+    let text = segments
+        .iter()
+        .filter_map(|segment| segment["text"].as_str())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(Transcript { text, language })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_are_trimmed_and_joined() {
+        let output = r#"{
+            "result": {"language": "fr"},
+            "transcription": [
+                {"timestamps": {"from": "00:00:00,000"}, "text": " Salut,"},
+                {"text": "   "},
+                {"text": " on se voit demain ?  "},
+                {"offsets": {"from": 0}}
+            ]
+        }"#;
+        let transcript = parse_whisper_json(output).unwrap();
+        assert_eq!(transcript.text, "Salut, on se voit demain ?");
+        assert_eq!(transcript.language, "fr");
+    }
+
+    #[test]
+    fn silence_is_an_empty_transcript() {
+        let output = r#"{"result": {"language": "en"}, "transcription": []}"#;
+        let transcript = parse_whisper_json(output).unwrap();
+        assert_eq!(transcript.text, "");
+        assert_eq!(transcript.language, "en");
+    }
+
+    #[test]
+    fn malformed_output_is_an_error() {
+        let outputs = [
+            "",
+            "not json",
+            r#"{"result": {"language": "en"}"#,
+            r#"{"transcription": [{"text": "hi"}]}"#,
+            r#"{"result": {"language": "en"}, "transcription": "hi"}"#,
+        ];
+        for output in outputs {
+            assert!(parse_whisper_json(output).is_err(), "{output}");
+        }
+    }
+}
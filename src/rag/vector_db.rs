@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use sqlx::{Pool, Postgres};
+
+use crate::rag::attachments::AttachmentConfig;
+use crate::rag::dataframes::ChunkConfig;
+use crate::rag::embedder::Embedder;
+use crate::rag::encryption::Cipher;
+use crate::rag::ingest::{Ingest, StoragePolicy};
+use crate::rag::retention::RetentionConfig;
+
+/// Postgres pool plus the embedder its vectors were made with, shared by every command.
+#[derive(Clone)]
+pub struct VectorDb {
+    pub pool: Pool<Postgres>,
+    pub embedder: Arc<dyn Embedder>,
+    pub chunking: ChunkConfig,
+    pub policy: StoragePolicy,
+    pub retention: RetentionConfig,
+    pub attachments: AttachmentConfig,
+    /// Seals attachment files and message bodies when encryption at rest is on.
+    pub cipher: Option<Cipher>,
+    /// Background worker new messages are queued on; stored inline when `None`.
+    pub ingest: Option<Ingest>,
+}
@@ -0,0 +1,385 @@
+use std::ops::Range;
+
+use chrono::DateTime;
+use clap::Args;
+use pgvector::Vector;
+use sqlx::{FromRow, Pool, Postgres};
+
+use crate::rag::dataframes::num_tokens_from_str;
+use crate::rag::encryption::open_body;
+use crate::rag::sqlx::ensure_active_model;
+use crate::rag::vector_db::VectorDb;
+
+#[derive(Args, Clone, Debug)]
+pub struct WindowConfig {
+    #[clap(
+        long = "window-messages",
+        default_value_t = 10,
+        help = "Most messages in one window"
+    )]
+    pub messages: usize,
+    #[clap(
+        long = "window-minutes",
+        default_value_t = 15,
+        help = "Longest time from the first to the last message of a window"
+    )]
+    pub minutes: i64,
+    #[clap(
+        long = "window-overlap",
+        default_value_t = 5,
+        help = "Messages a window shares with the next one"
+    )]
+    pub overlap: usize,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            messages: 10,
+            minutes: 15,
+            overlap: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, FromRow)]
+pub struct WindowMessage {
+    pub id: i64,
+    pub body: Option<String>,
+    pub direction: Option<String>,
+    pub contact: Option<String>,
+    pub group_name: Option<String>,
+    pub sent_at: i64,
+}
+
+impl WindowMessage {
+    /// Transcript line, e.g. `14:02 Alice: see you at 5`.
+    pub fn to_line(&self) -> String {
+        let time = DateTime::from_timestamp_millis(self.sent_at)
+            .map(|time| time.format("%H:%M").to_string())
+            .unwrap_or_default();
+        format!(
+            "{time} {}: {}",
+            speaker(self.direction.as_deref(), self.contact.as_deref()),
+            self.body.clone().unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WindowStats {
+    pub threads: usize,
+    pub created: usize,
+    pub removed: usize,
+}
+
+/// Name of whoever wrote a message, from the `name,uuid` stored by `format_contact`.
+pub fn speaker(direction: Option<&str>, contact: Option<&str>) -> String {
+    match (direction, contact) {
+        (Some("to"), _) => String::from("Me"),
+        (_, Some(contact)) => contact
+            .split_once(',')
+            .map_or(contact, |(name, _)| name)
+            .to_string(),
+        (_, None) => String::from("Unknown"),
+    }
+}
+
+/// Splits the messages of one thread, oldest first, into sliding windows.
+///
+/// A window ends when it holds `config.messages` messages, spans more than
+/// `config.minutes`, or would exceed `max_tokens`; the next one starts
+/// `config.overlap` messages before that end.
+pub fn plan_windows(
+    lines: &[(i64, usize)],
+    config: &WindowConfig,
+    max_tokens: usize,
+) -> Vec<Range<usize>> {
+    let size = config.messages.max(1);
+    let span = config.minutes * 60 * 1000;
+    let mut windows = vec![];
+    let mut start = 0;
+
+    while start < lines.len() {
+        let (first_sent_at, mut tokens) = lines[start];
+        let mut end = start + 1;
+        while end < lines.len() && end - start < size {
+            let (sent_at, line_tokens) = lines[end];
+            if sent_at - first_sent_at > span || tokens + line_tokens > max_tokens {
+                break;
+            }
+            tokens += line_tokens;
+            end += 1;
+        }
+        // Cut short by a pause, so it only repeats the tail of the previous window
+        if windows.last().is_some_and(|last: &Range<usize>| last.end >= end) {
+            start = end;
+            continue;
+        }
+        windows.push(start..end);
+
+        if end == lines.len() {
+            break;
+        }
+        // Always move forward, even when a window is shorter than the overlap
+        start = (end - config.overlap.min(end - start)).max(start + 1);
+    }
+    windows
+}
+
+/// Builds the windows of every thread, or only `thread`, embedding only windows
+/// that are new and removing those that no longer match the stored messages.
+pub async fn build_windows(
+    vector_db: &VectorDb,
+    config: &WindowConfig,
+    thread: Option<&str>,
+) -> anyhow::Result<WindowStats> {
+    let threads: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT thread_id FROM embeddings
+        WHERE thread_id IS NOT NULL AND ($1::text IS NULL OR thread_id = $1)
+        "#,
+    )
+    .bind(thread)
+    .fetch_all(&vector_db.pool)
+    .await?;
+
+    let mut stats = WindowStats::default();
+    for thread_id in threads {
+        build_thread_windows(vector_db, config, &thread_id, &mut stats).await?;
+        stats.threads += 1;
+    }
+    Ok(stats)
+}
+
+async fn build_thread_windows(
+    vector_db: &VectorDb,
+    config: &WindowConfig,
+    thread_id: &str,
+    stats: &mut WindowStats,
+) -> anyhow::Result<()> {
+    // The first chunk stands in for long messages
+    let mut messages: Vec<WindowMessage> = sqlx::query_as(
+        r#"
+        SELECT id, body, direction, contact, group_name, sent_at FROM embeddings
+        WHERE thread_id = $1 AND attachment = '' AND chunk_index = 0 AND sent_at IS NOT NULL
+        ORDER BY sent_at, id
+        "#,
+    )
+    .bind(thread_id)
+    .fetch_all(&vector_db.pool)
+    .await?;
+    for message in &mut messages {
+        message.body = open_body(vector_db.cipher.as_ref(), message.body.take());
+    }
+
+    let lines: Vec<String> = messages.iter().map(WindowMessage::to_line).collect();
+    let line_tokens: Vec<(i64, usize)> = messages
+        .iter()
+        .zip(&lines)
+        .map(|(message, line)| (message.sent_at, num_tokens_from_str(line)))
+        .collect();
+    let planned = plan_windows(&line_tokens, config, vector_db.chunking.size);
+
+    let existing: Vec<(i64, i64, i64, i32)> = sqlx::query_as(
+        r#"
+        SELECT id, started_at, ended_at, message_count FROM conversation_windows
+        WHERE thread_id = $1
+        "#,
+    )
+    .bind(thread_id)
+    .fetch_all(&vector_db.pool)
+    .await?;
+
+    let key = |range: &Range<usize>| {
+        (
+            messages[range.start].sent_at,
+            messages[range.end - 1].sent_at,
+            range.len() as i32,
+        )
+    };
+    let stale: Vec<i64> = existing
+        .iter()
+        .filter(|(_, started_at, ended_at, count)| {
+            !planned
+                .iter()
+                .any(|range| key(range) == (*started_at, *ended_at, *count))
+        })
+        .map(|(id, ..)| *id)
+        .collect();
+    let new: Vec<&Range<usize>> = planned
+        .iter()
+        .filter(|range| {
+            !existing.iter().any(|(_, started_at, ended_at, count)| {
+                key(range) == (*started_at, *ended_at, *count)
+            })
+        })
+        .collect();
+
+    if !stale.is_empty() {
+        sqlx::query("DELETE FROM conversation_windows WHERE id = ANY($1)")
+            .bind(&stale)
+            .execute(&vector_db.pool)
+            .await?;
+        stats.removed += stale.len();
+    }
+
+    for ranges in new.chunks(32) {
+        let transcripts: Vec<String> = ranges
+            .iter()
+            .map(|range| lines[(*range).clone()].join("\n"))
+            .collect();
+        let embeddings = vector_db.embedder.embed_batch(&transcripts).await?;
+        if embeddings.len() != transcripts.len() {
+            anyhow::bail!(
+                "expected {} embeddings, got {}",
+                transcripts.len(),
+                embeddings.len()
+            );
+        }
+
+        for ((range, transcript), embedding) in ranges.iter().zip(transcripts).zip(embeddings) {
+            let members = &messages[(*range).clone()];
+            let tokens: usize = line_tokens[(*range).clone()].iter().map(|(_, t)| t).sum();
+            let body = match &vector_db.cipher {
+                Some(cipher) => cipher.seal_text(&transcript),
+                None => transcript,
+            };
+            insert_window(
+                &vector_db.pool,
+                thread_id,
+                members,
+                &body,
+                tokens as i32,
+                Vector::from(embedding),
+                vector_db.embedder.model(),
+            )
+            .await?;
+            stats.created += 1;
+        }
+    }
+
+    Ok(())
+}
+
+async fn insert_window(
+    pool: &Pool<Postgres>,
+    thread_id: &str,
+    members: &[WindowMessage],
+    transcript: &str,
+    tokens: i32,
+    embedding: Vector,
+    embedding_model: &str,
+) -> anyhow::Result<()> {
+    let first = &members[0];
+    let last = &members[members.len() - 1];
+    // Group windows are labelled by group, direct ones by the other person
+    let contact = match first.group_name {
+        Some(_) => None,
+        None => members.iter().find_map(|message| message.contact.clone()),
+    };
+
+    let mut tx = pool.begin().await?;
+    let window_id: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO conversation_windows
+            (thread_id, contact, group_name, started_at, ended_at, message_count, body, tokens, embedding, embedding_model)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        RETURNING id
+        "#,
+    )
+    .bind(thread_id)
+    .bind(contact)
+    .bind(&first.group_name)
+    .bind(first.sent_at)
+    .bind(last.sent_at)
+    .bind(members.len() as i32)
+    .bind(transcript)
+    .bind(tokens)
+    .bind(embedding)
+    .bind(embedding_model)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let ids: Vec<i64> = members.iter().map(|message| message.id).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO conversation_window_messages (window_id, embedding_id, position)
+        SELECT $1, id, position::integer - 1 FROM unnest($2::bigint[]) WITH ORDINALITY AS m(id, position)
+        "#,
+    )
+    .bind(window_id)
+    .bind(&ids)
+    .execute(&mut *tx)
+    .await?;
+    ensure_active_model(&mut tx, embedding_model).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60 * 1000;
+
+    /// Lines of `tokens` tokens sent at the given minutes.
+    fn lines(minutes: &[i64], tokens: usize) -> Vec<(i64, usize)> {
+        minutes
+            .iter()
+            .map(|minute| (minute * MINUTE, tokens))
+            .collect()
+    }
+
+    #[test]
+    fn no_messages_have_no_windows() {
+        assert!(plan_windows(&[], &WindowConfig::default(), 1000).is_empty());
+    }
+
+    #[test]
+    fn a_short_conversation_is_one_window() {
+        let lines = lines(&[0, 1, 2, 3], 10);
+        assert_eq!(
+            plan_windows(&lines, &WindowConfig::default(), 1000),
+            vec![0..4]
+        );
+    }
+
+    #[test]
+    fn windows_slide_by_the_overlap() {
+        let minutes: Vec<i64> = (0..25).map(|i| i / 2).collect();
+        let lines = lines(&minutes, 10);
+        assert_eq!(
+            plan_windows(&lines, &WindowConfig::default(), 1000),
+            vec![0..10, 5..15, 10..20, 15..25]
+        );
+    }
+
+    #[test]
+    fn a_pause_ends_the_window_without_repeating_its_tail() {
+        let lines = lines(&[0, 1, 2, 60, 61], 10);
+        assert_eq!(
+            plan_windows(&lines, &WindowConfig::default(), 1000),
+            vec![0..3, 3..5]
+        );
+    }
+
+    #[test]
+    fn windows_stay_under_the_token_limit() {
+        let lines = lines(&[0, 1, 2, 3], 40);
+        assert_eq!(
+            plan_windows(&lines, &WindowConfig::default(), 100),
+            vec![0..2, 1..3, 2..4]
+        );
+    }
+
+    #[test]
+    fn a_message_over_the_token_limit_is_still_a_window() {
+        let lines = lines(&[0], 500);
+        assert_eq!(
+            plan_windows(&lines, &WindowConfig::default(), 100),
+            vec![0..1]
+        );
+    }
+}
@@ -1,18 +1,15 @@
-use std::fs;
+use std::path::{Path, PathBuf};
 
 use tracing::info;
 
-pub async fn attachments_dir() -> anyhow::Result<String> {
-    let attachments_dir = "attachments";
-    let exists = tokio::fs::try_exists(&attachments_dir)
-        .await
-        .unwrap_or(false);
-    if !exists {
-        _ = fs::create_dir(&attachments_dir).expect("ERROR: create attachments dir failed");
-    }
+/// Default root attachments are stored under, relative to the working directory.
+pub const ATTACHMENTS_DIR: &str = "attachments";
+
+pub async fn attachments_dir(root: &Path) -> anyhow::Result<PathBuf> {
+    tokio::fs::create_dir_all(root).await?;
     info!(
-        path =% attachments_dir,
+        path =% root.display(),
         "attachments will be stored"
     );
-    Ok(attachments_dir.to_string())
+    Ok(root.to_path_buf())
 }
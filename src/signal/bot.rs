@@ -0,0 +1,309 @@
+use std::time::UNIX_EPOCH;
+
+use clap::Args;
+use presage::libsignal_service::prelude::Uuid;
+use presage::libsignal_service::proto::data_message::Quote;
+use presage::libsignal_service::protocol::ServiceId;
+use presage::libsignal_service::zkgroup::GroupMasterKeyBytes;
+use presage::proto::body_range::AssociatedValue;
+use presage::{
+    libsignal_service::content::{Content, ContentBody, DataMessage, GroupContextV2},
+    manager::Registered,
+    store::{Store, Thread},
+    Manager,
+};
+use tracing::info;
+
+use crate::rag::ask::{ask, AskOptions};
+use crate::rag::generate::DEFAULT_OLLAMA_URL;
+use crate::rag::search::SearchFilter;
+use crate::rag::vector_db::VectorDb;
+use crate::signal::format::format_thread_key;
+use crate::signal::parse_group_master_key;
+
+/// Placeholder Signal puts in the body where a mention is rendered.
+const MENTION_PLACEHOLDER: char = '\u{FFFC}';
+
+#[derive(Args, Clone, Debug)]
+pub struct BotConfig {
+    #[clap(
+        long = "bot",
+        help = "Answer questions sent over Signal from allowlisted chats"
+    )]
+    pub enabled: bool,
+    #[clap(
+        long = "bot-trigger",
+        default_value = "/ask",
+        help = "Prefix that marks a message as a question"
+    )]
+    pub trigger: String,
+    #[clap(
+        long = "bot-allow-contact",
+        value_name = "UUID",
+        help = "Contact whose direct messages are answered, can be repeated"
+    )]
+    pub allowed_contacts: Vec<Uuid>,
+    #[clap(
+        long = "bot-allow-group",
+        value_name = "HEX",
+        value_parser = parse_group_master_key,
+        help = "Master Key of a group where questions are answered, can be repeated"
+    )]
+    pub allowed_groups: Vec<GroupMasterKeyBytes>,
+    #[clap(
+        long = "bot-model",
+        default_value = "llama3.2",
+        help = "Ollama model used to answer"
+    )]
+    pub model: String,
+    /// Ollama server answering, from the global `--ollama-url`.
+    #[clap(skip = String::from(DEFAULT_OLLAMA_URL))]
+    pub ollama_url: String,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            enabled: false,
+            trigger: String::from("/ask"),
+            allowed_contacts: vec![],
+            allowed_groups: vec![],
+            model: String::from("llama3.2"),
+            ollama_url: String::from(DEFAULT_OLLAMA_URL),
+        }
+    }
+}
+
+/// A question for the bot, taken from a received message.
+pub struct Question {
+    thread: Thread,
+    text: String,
+    /// The message that asked, which the reply quotes.
+    asked_at: u64,
+    author: Uuid,
+    body: String,
+}
+
+/// An answer ready to be sent in the chat its question came from.
+pub struct Reply {
+    thread: Thread,
+    message: DataMessage,
+    timestamp: u64,
+}
+
+/// Returns the question `content` asks the bot, if it comes from an allowlisted chat.
+pub fn question_for_bot<S: Store>(
+    manager: &Manager<S, Registered>,
+    content: &Content,
+    config: &BotConfig,
+) -> Option<Question> {
+    if !config.enabled {
+        return None;
+    }
+
+    // Only messages received from others, never our own synced ones.
+    let ContentBody::DataMessage(data_message) = &content.body else {
+        return None;
+    };
+    let body = data_message.body.as_ref()?;
+    let thread = Thread::try_from(content).ok()?;
+
+    let allowed = match &thread {
+        Thread::Contact(uuid) => config.allowed_contacts.contains(uuid),
+        Thread::Group(key) => config.allowed_groups.contains(key),
+    };
+    if !allowed {
+        return None;
+    }
+
+    let our_aci = manager.registration_data().service_ids.aci;
+    let text = extract_question(&thread, data_message, body, &our_aci, config)?;
+    Some(Question {
+        thread,
+        text,
+        asked_at: data_message.timestamp.unwrap_or(content.metadata.timestamp),
+        author: content.metadata.sender.raw_uuid(),
+        body: body.clone(),
+    })
+}
+
+/// Answers `question` from the history of its own chat, so a chat never sees another
+/// chat's messages. Retrieval and generation are slow, so this runs off the receive loop.
+pub async fn answer_question(
+    vector_db: &VectorDb,
+    question: Question,
+    config: &BotConfig,
+) -> anyhow::Result<Reply> {
+    let options = AskOptions {
+        ollama_url: config.ollama_url.clone(),
+        model: config.model.clone(),
+        filter: SearchFilter {
+            thread: Some(format_thread_key(&question.thread)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let answer = ask(vector_db, &question.text, &options).await?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    let message = DataMessage {
+        body: Some(answer.answer),
+        timestamp: Some(timestamp),
+        quote: Some(Quote {
+            id: Some(question.asked_at),
+            author_aci: Some(question.author.to_string()),
+            text: Some(question.body),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    Ok(Reply {
+        thread: question.thread,
+        message,
+        timestamp,
+    })
+}
+
+/// Sends `reply`, quoting the question, in the chat it was asked in.
+pub async fn send_reply<S: Store>(
+    manager: &mut Manager<S, Registered>,
+    reply: Reply,
+) -> anyhow::Result<()> {
+    let Reply {
+        thread,
+        message,
+        timestamp,
+    } = reply;
+    match thread {
+        Thread::Contact(uuid) => {
+            info!(recipient =% uuid, "bot answering contact");
+            manager
+                .send_message(ServiceId::Aci(uuid.into()), message, timestamp)
+                .await?;
+        }
+        Thread::Group(master_key) => {
+            info!("bot answering group");
+            let message = DataMessage {
+                group_v2: Some(GroupContextV2 {
+                    master_key: Some(master_key.to_vec()),
+                    revision: Some(0),
+                    ..Default::default()
+                }),
+                ..message
+            };
+            manager
+                .send_message_to_group(&master_key, message, timestamp)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// A question is either prefixed with the trigger, mentions us, or is a direct message.
+fn extract_question(
+    thread: &Thread,
+    data_message: &DataMessage,
+    body: &str,
+    our_aci: &Uuid,
+    config: &BotConfig,
+) -> Option<String> {
+    let mentions_us = data_message.body_ranges.iter().any(|range| {
+        matches!(
+            &range.associated_value,
+            Some(AssociatedValue::MentionAci(aci)) if aci == &our_aci.to_string()
+        )
+    });
+
+    let question = if let Some(question) = body.trim().strip_prefix(&config.trigger) {
+        question.to_string()
+    } else if mentions_us {
+        body.replace(MENTION_PLACEHOLDER, "")
+    } else if let Thread::Contact(_) = thread {
+        body.to_string()
+    } else {
+        return None;
+    };
+
+    Some(question.trim().to_string()).filter(|question| !question.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use presage::proto::BodyRange;
+
+    const OURS: Uuid = Uuid::from_u128(1);
+    const THEIRS: Uuid = Uuid::from_u128(2);
+
+    fn group() -> Thread {
+        Thread::Group([7; 32])
+    }
+
+    fn mentioning(aci: &Uuid) -> DataMessage {
+        DataMessage {
+            body_ranges: vec![BodyRange {
+                start: Some(0),
+                length: Some(1),
+                associated_value: Some(AssociatedValue::MentionAci(aci.to_string())),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn question(thread: &Thread, data_message: &DataMessage, body: &str) -> Option<String> {
+        extract_question(thread, data_message, body, &OURS, &BotConfig::default())
+    }
+
+    #[test]
+    fn the_trigger_marks_a_question_in_groups() {
+        assert_eq!(
+            question(&group(), &DataMessage::default(), " /ask when is dinner? "),
+            Some(String::from("when is dinner?"))
+        );
+    }
+
+    #[test]
+    fn other_group_messages_are_not_questions() {
+        assert_eq!(
+            question(&group(), &DataMessage::default(), "when is dinner?"),
+            None
+        );
+    }
+
+    #[test]
+    fn a_mention_marks_a_question_without_the_placeholder() {
+        assert_eq!(
+            question(&group(), &mentioning(&OURS), "\u{FFFC} where do we meet?"),
+            Some(String::from("where do we meet?"))
+        );
+        assert_eq!(
+            question(&group(), &mentioning(&THEIRS), "\u{FFFC} where do we meet?"),
+            None
+        );
+    }
+
+    #[test]
+    fn direct_messages_are_questions() {
+        assert_eq!(
+            question(
+                &Thread::Contact(THEIRS),
+                &DataMessage::default(),
+                "when is dinner?"
+            ),
+            Some(String::from("when is dinner?"))
+        );
+    }
+
+    #[test]
+    fn an_empty_question_is_ignored() {
+        assert_eq!(question(&group(), &DataMessage::default(), "/ask  "), None);
+        assert_eq!(
+            question(&Thread::Contact(THEIRS), &DataMessage::default(), " "),
+            None
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use presage::libsignal_service::content::Reaction;
 use presage::libsignal_service::prelude::Uuid;
-use presage::libsignal_service::proto::data_message::Quote;
+use presage::libsignal_service::proto::data_message::{Delete, Quote};
 use presage::{
     libsignal_service::content::{ContentBody, DataMessage},
     manager::Registered,
@@ -9,11 +9,48 @@ use presage::{
 };
 use tracing::warn;
 
+use crate::signal::format_message::MessageKind;
+
 pub async fn format_data_message<S: Store>(
     thread: &Thread,
     data_message: &DataMessage,
     manager: &Manager<S, Registered>,
-) -> Option<String> {
+) -> Option<(MessageKind, String)> {
+    let (kind, body) = classify_data_message(data_message);
+    let (
+        MessageKind::Reaction,
+        Some(Reaction {
+            target_sent_timestamp: Some(ts),
+            emoji: Some(emoji),
+            ..
+        }),
+    ) = (kind, &data_message.reaction)
+    else {
+        return Some((kind, body));
+    };
+
+    // Quote the message reacted to when it is in the store
+    let Ok(Some(message)) = manager.store().message(thread, *ts).await else {
+        warn!(%thread, sent_at = ts, "no message found in thread");
+        return Some((kind, body));
+    };
+    let ContentBody::DataMessage(DataMessage {
+        body: Some(reacted_to),
+        ..
+    }) = message.body
+    else {
+        warn!("message reacted to has no body");
+        return Some((kind, body));
+    };
+    Some((
+        kind,
+        format!("Reacted with {emoji} to message: \"{reacted_to}\""),
+    ))
+}
+
+/// Kind and text of a data message, from the fields it carries rather than its text.
+/// Reactions are still a reaction when the target is unknown, so they are recorded.
+pub fn classify_data_message(data_message: &DataMessage) -> (MessageKind, String) {
     match data_message {
         DataMessage {
             quote:
@@ -23,38 +60,61 @@ pub async fn format_data_message<S: Store>(
                 }),
             body: Some(body),
             ..
-        } => Some(format!("Answer to message \"{quoted_text}\": {body}")),
+        } => (
+            MessageKind::Quote,
+            format!("Answer to message \"{quoted_text}\": {body}"),
+        ),
         DataMessage {
             reaction:
                 Some(Reaction {
-                    target_sent_timestamp: Some(ts),
+                    target_sent_timestamp: Some(_),
                     emoji: Some(emoji),
                     ..
                 }),
             ..
-        } => {
-            let Ok(Some(message)) = manager.store().message(thread, *ts).await else {
-                warn!(%thread, sent_at = ts, "no message found in thread");
-                return None;
-            };
-
-            let ContentBody::DataMessage(DataMessage {
-                body: Some(body), ..
-            }) = message.body
-            else {
-                warn!("message reacted to has no body");
-                return None;
-            };
-
-            Some(format!("Reacted with {emoji} to message: \"{body}\""))
-        }
+        } => (
+            MessageKind::Reaction,
+            format!("Reacted with {emoji} to a message"),
+        ),
+        DataMessage {
+            delete: Some(Delete {
+                target_sent_timestamp: Some(ts),
+            }),
+            ..
+        } => (MessageKind::Delete, format!("Deleted message sent at {ts}")),
         DataMessage {
             body: Some(body), ..
-        } => Some(body.to_string()),
-        _ => Some("Empty data message".to_string()),
+        } => (MessageKind::Text, body.to_string()),
+        DataMessage { attachments, .. } if !attachments.is_empty() => (
+            MessageKind::Attachment,
+            attachments_body(attachments.iter().map(|attachment| {
+                (
+                    attachment.file_name.as_deref(),
+                    attachment.content_type.as_deref(),
+                )
+            })),
+        ),
+        _ => (MessageKind::Null, "Empty data message".to_string()),
     }
 }
 
+/// Body of a message that only carries attachments, naming each by its file name, else
+/// its mime type, so the message itself can be found and shown. Photos come unnamed.
+pub fn attachments_body<'a>(
+    attachments: impl IntoIterator<Item = (Option<&'a str>, Option<&'a str>)>,
+) -> String {
+    let names: Vec<&str> = attachments
+        .into_iter()
+        .map(|(file_name, mime_type)| match (file_name, mime_type) {
+            (Some(file_name), _) => file_name,
+            (None, Some(mime_type)) if mime_type.starts_with("image/") => "photo",
+            (None, Some(mime_type)) => mime_type,
+            (None, None) => "file",
+        })
+        .collect();
+    format!("Attachment: {}", names.join(", "))
+}
+
 pub async fn format_contact<S: Store>(uuid: &Uuid, manager: &Manager<S, Registered>) -> String {
     manager
         .store()
@@ -67,6 +127,14 @@ pub async fn format_contact<S: Store>(uuid: &Uuid, manager: &Manager<S, Register
         .unwrap_or_else(|| uuid.to_string())
 }
 
+/// Stable key of a thread: the contact uuid or the hex group master key.
+pub fn format_thread_key(thread: &Thread) -> String {
+    match thread {
+        Thread::Contact(uuid) => uuid.to_string(),
+        Thread::Group(key) => hex::encode(key),
+    }
+}
+
 pub async fn format_group<S: Store>(key: [u8; 32], manager: &Manager<S, Registered>) -> String {
     manager
         .store()
@@ -77,3 +145,89 @@ pub async fn format_group<S: Store>(key: [u8; 32], manager: &Manager<S, Register
         .map(|g| g.title)
         .unwrap_or_else(|| "<missing group>".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use presage::proto::AttachmentPointer;
+
+    use super::*;
+
+    fn text(body: &str) -> DataMessage {
+        DataMessage {
+            body: Some(body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn texts_that_look_like_events_stay_text() {
+        // Stored as the events they read like when kinds came from the text
+        for body in [
+            "is typing...",
+            "is calling!",
+            "Null message (for example deleted)",
+        ] {
+            assert_eq!(
+                classify_data_message(&text(body)),
+                (MessageKind::Text, body.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn data_messages_are_classified_by_their_fields() {
+        let quote = DataMessage {
+            quote: Some(Quote {
+                text: Some(String::from("dinner?")),
+                ..Default::default()
+            }),
+            ..text("sure")
+        };
+        let reaction = DataMessage {
+            reaction: Some(Reaction {
+                emoji: Some(String::from("👍")),
+                target_sent_timestamp: Some(1000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let delete = DataMessage {
+            delete: Some(Delete {
+                target_sent_timestamp: Some(1000),
+            }),
+            ..Default::default()
+        };
+        let attachment = DataMessage {
+            attachments: vec![AttachmentPointer {
+                content_type: Some(String::from("image/jpeg")),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let cases = [
+            (
+                quote,
+                MessageKind::Quote,
+                "Answer to message \"dinner?\": sure",
+            ),
+            (
+                reaction,
+                MessageKind::Reaction,
+                "Reacted with 👍 to a message",
+            ),
+            (delete, MessageKind::Delete, "Deleted message sent at 1000"),
+            (attachment, MessageKind::Attachment, "Attachment: photo"),
+            (
+                DataMessage::default(),
+                MessageKind::Null,
+                "Empty data message",
+            ),
+        ];
+        for (data_message, kind, body) in cases {
+            assert_eq!(
+                classify_data_message(&data_message),
+                (kind, body.to_string())
+            );
+        }
+    }
+}
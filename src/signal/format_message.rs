@@ -1,10 +1,11 @@
+use clap::ValueEnum;
 use presage::libsignal_service::proto::sync_message::Sent;
 use presage::proto::receipt_message;
 use presage::proto::EditMessage;
 use presage::proto::ReceiptMessage;
 use presage::proto::SyncMessage;
 use presage::{
-    libsignal_service::content::{Content, ContentBody},
+    libsignal_service::content::{Content, ContentBody, DataMessage},
     manager::Registered,
     store::{Store, Thread},
     Manager,
@@ -29,29 +30,88 @@ impl Direction {
     }
 }
 
+/// What a Signal message is, independent of the text it is displayed as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MessageKind {
+    /// Message with a body.
+    Text,
+    /// Reply quoting an earlier message.
+    Quote,
+    Reaction,
+    /// New version of an earlier message.
+    Edit,
+    /// Remote delete of an earlier message.
+    Delete,
+    Receipt,
+    Typing,
+    Call,
+    Story,
+    /// Sync message from one of our devices other than a sent message.
+    Sync,
+    /// Data message with attachments but no text.
+    Attachment,
+    /// Text read from an attachment, stored on rows of its own.
+    Extracted,
+    /// Null message, or data message without content.
+    Null,
+    /// Content that could not be understood.
+    Error,
+}
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::Quote => "quote",
+            MessageKind::Reaction => "reaction",
+            MessageKind::Edit => "edit",
+            MessageKind::Delete => "delete",
+            MessageKind::Receipt => "receipt",
+            MessageKind::Typing => "typing",
+            MessageKind::Call => "call",
+            MessageKind::Story => "story",
+            MessageKind::Sync => "sync",
+            MessageKind::Attachment => "attachment",
+            MessageKind::Extracted => "extracted",
+            MessageKind::Null => "null",
+            MessageKind::Error => "error",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MessageEverything {
+    pub kind: MessageKind,
     pub direction: Option<Direction>,
     pub contact: Option<String>,
     pub group: Option<String>,
     pub body: Option<String>,
+    /// Sent timestamp of the message an edit, delete or reaction refers to.
+    pub target_sent_at: Option<u64>,
+    /// Disappearing-message timer of the thread in seconds, `Some(0)` when off.
+    pub expire_timer: Option<u32>,
 }
 
 impl MessageEverything {
     pub fn default() -> MessageEverything {
         MessageEverything {
+            kind: MessageKind::Null,
             direction: None,
             contact: None,
             group: None,
             body: None,
+            target_sent_at: None,
+            expire_timer: None,
         }
     }
     pub fn error(error: String) -> MessageEverything {
         MessageEverything {
+            kind: MessageKind::Error,
             direction: None,
             contact: None,
             group: None,
             body: Some(error),
+            target_sent_at: None,
+            expire_timer: None,
         }
     }
 }
@@ -67,26 +127,27 @@ pub async fn format_message<S: Store>(
     };
 
     enum Msg<'a> {
-        Received(&'a Thread, String),
-        Sent(&'a Thread, String),
+        Received(&'a Thread, MessageKind, String),
+        Sent(&'a Thread, MessageKind, String),
     }
 
     if let Some(msg) = match &content.body {
         ContentBody::NullMessage(_) => Some(Msg::Received(
             &thread,
+            MessageKind::Null,
             "Null message (for example deleted)".to_string(),
         )),
         ContentBody::DataMessage(data_message) => {
             format_data_message(&thread, data_message, manager)
                 .await
-                .map(|body| Msg::Received(&thread, body))
+                .map(|(kind, body)| Msg::Received(&thread, kind, body))
         }
         ContentBody::EditMessage(EditMessage {
             data_message: Some(data_message),
             ..
         }) => format_data_message(&thread, data_message, manager)
             .await
-            .map(|body| Msg::Received(&thread, body)),
+            .map(|(_, body)| Msg::Received(&thread, MessageKind::Edit, body)),
         ContentBody::EditMessage(EditMessage { .. }) => None,
         ContentBody::SynchronizeMessage(SyncMessage {
             sent:
@@ -97,7 +158,7 @@ pub async fn format_message<S: Store>(
             ..
         }) => format_data_message(&thread, data_message, manager)
             .await
-            .map(|body| Msg::Sent(&thread, body)),
+            .map(|(kind, body)| Msg::Sent(&thread, kind, body)),
         ContentBody::SynchronizeMessage(SyncMessage {
             sent:
                 Some(Sent {
@@ -111,63 +172,92 @@ pub async fn format_message<S: Store>(
             ..
         }) => format_data_message(&thread, data_message, manager)
             .await
-            .map(|body| Msg::Sent(&thread, body)),
-        ContentBody::SynchronizeMessage(SyncMessage { .. }) => None,
-        ContentBody::CallMessage(_) => Some(Msg::Received(&thread, "is calling!".into())),
-        ContentBody::TypingMessage(_) => Some(Msg::Received(&thread, "is typing...".into())),
+            .map(|(_, body)| Msg::Sent(&thread, MessageKind::Edit, body)),
+        ContentBody::SynchronizeMessage(SyncMessage { .. }) => Some(Msg::Sent(
+            &thread,
+            MessageKind::Sync,
+            "synced from another device".into(),
+        )),
+        ContentBody::CallMessage(_) => Some(Msg::Received(
+            &thread,
+            MessageKind::Call,
+            "is calling!".into(),
+        )),
+        ContentBody::TypingMessage(_) => Some(Msg::Received(
+            &thread,
+            MessageKind::Typing,
+            "is typing...".into(),
+        )),
         ContentBody::ReceiptMessage(ReceiptMessage {
             r#type: receipt_type,
             timestamp,
         }) => Some(Msg::Received(
             &thread,
+            MessageKind::Receipt,
             format!(
                 "got {:?} receipt for messages sent at {timestamp:?}",
                 receipt_message::Type::try_from(receipt_type.unwrap_or_default()).unwrap()
             ),
         )),
-        ContentBody::StoryMessage(story) => {
-            Some(Msg::Received(&thread, format!("new story: {story:?}")))
-        }
-        ContentBody::PniSignatureMessage(_) => {
-            Some(Msg::Received(&thread, "got PNI signature message".into()))
-        }
+        ContentBody::StoryMessage(story) => Some(Msg::Received(
+            &thread,
+            MessageKind::Story,
+            format!("new story: {story:?}"),
+        )),
+        ContentBody::PniSignatureMessage(_) => Some(Msg::Received(
+            &thread,
+            MessageKind::Sync,
+            "got PNI signature message".into(),
+        )),
     } {
         match msg {
-            Msg::Received(Thread::Contact(sender), body) => {
+            Msg::Received(Thread::Contact(sender), kind, body) => {
                 let contact = format_contact(sender, manager).await;
                 MessageEverything {
+                    kind,
                     direction: Some(Direction::From),
                     contact: Some(contact),
                     group: None,
                     body: Some(body),
+                    target_sent_at: target_sent_at(content),
+                    expire_timer: data_message(content).and_then(|m| m.expire_timer),
                 }
             }
-            Msg::Sent(Thread::Contact(recipient), body) => {
+            Msg::Sent(Thread::Contact(recipient), kind, body) => {
                 let contact = format_contact(recipient, manager).await;
                 MessageEverything {
+                    kind,
                     direction: Some(Direction::To),
                     contact: Some(contact),
                     group: None,
                     body: Some(body),
+                    target_sent_at: target_sent_at(content),
+                    expire_timer: data_message(content).and_then(|m| m.expire_timer),
                 }
             }
-            Msg::Received(Thread::Group(key), body) => {
+            Msg::Received(Thread::Group(key), kind, body) => {
                 let sender = format_contact(&content.metadata.sender.raw_uuid(), manager).await;
                 let group = format_group(*key, manager).await;
                 MessageEverything {
+                    kind,
                     direction: Some(Direction::From),
                     contact: Some(sender),
                     group: Some(group),
                     body: Some(body),
+                    target_sent_at: target_sent_at(content),
+                    expire_timer: data_message(content).and_then(|m| m.expire_timer),
                 }
             }
-            Msg::Sent(Thread::Group(key), body) => {
+            Msg::Sent(Thread::Group(key), kind, body) => {
                 let group = format_group(*key, manager).await;
                 MessageEverything {
+                    kind,
                     direction: Some(Direction::To),
                     contact: None,
                     group: Some(group),
                     body: Some(body),
+                    target_sent_at: target_sent_at(content),
+                    expire_timer: data_message(content).and_then(|m| m.expire_timer),
                 }
             }
         }
@@ -175,3 +265,55 @@ pub async fn format_message<S: Store>(
         MessageEverything::error(String::from("Something went wrong!"))
     }
 }
+
+fn target_sent_at(content: &Content) -> Option<u64> {
+    match &content.body {
+        ContentBody::EditMessage(EditMessage {
+            target_sent_timestamp,
+            ..
+        }) => *target_sent_timestamp,
+        ContentBody::SynchronizeMessage(SyncMessage {
+            sent:
+                Some(Sent {
+                    edit_message:
+                        Some(EditMessage {
+                            target_sent_timestamp,
+                            ..
+                        }),
+                    ..
+                }),
+            ..
+        }) => *target_sent_timestamp,
+        _ => data_message(content).and_then(|data_message| {
+            data_message
+                .delete
+                .as_ref()
+                .and_then(|delete| delete.target_sent_timestamp)
+                .or(data_message
+                    .reaction
+                    .as_ref()
+                    .and_then(|reaction| reaction.target_sent_timestamp))
+        }),
+    }
+}
+
+/// The data message carried by `content`, whether received, sent from another
+/// of our devices, or inside an edit.
+pub fn data_message(content: &Content) -> Option<&DataMessage> {
+    match &content.body {
+        ContentBody::DataMessage(data_message) => Some(data_message),
+        ContentBody::EditMessage(EditMessage { data_message, .. }) => data_message.as_ref(),
+        ContentBody::SynchronizeMessage(SyncMessage {
+            sent:
+                Some(Sent {
+                    message,
+                    edit_message,
+                    ..
+                }),
+            ..
+        }) => message.as_ref().or(edit_message
+            .as_ref()
+            .and_then(|edit| edit.data_message.as_ref())),
+        _ => None,
+    }
+}
@@ -0,0 +1,765 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context as _;
+use base64::prelude::*;
+use clap::ValueEnum;
+use futures::TryStreamExt;
+use presage::libsignal_service::proto::attachment_pointer::Flags as AttachmentFlags;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use sqlx::Connection;
+use tracing::{error, info, warn};
+
+use crate::rag::{
+    attachment_store::{save_attachment, AttachmentMeta},
+    ingest::store_batch,
+    vector_db::VectorDb,
+};
+
+use super::format::attachments_body;
+use super::format_message::{Direction, MessageKind};
+use super::process_incoming_message::{apply_message_effects, ProcessedMessage};
+
+// Messages embedded per request
+const BATCH_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// Signal Desktop's `db.sqlite`, with the SQLCipher encryption removed.
+    DesktopDb,
+    /// A JSON object with the `conversations` and `messages` Signal Desktop stores,
+    /// as written by backup tools.
+    Json,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    pub conversations: usize,
+    pub messages: usize,
+    /// Deleted, service or unattributable messages.
+    pub skipped: usize,
+}
+
+/// What Signal Desktop keeps per conversation, in its own JSON field names.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopConversation {
+    id: String,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    service_id: Option<String>,
+    uuid: Option<String>,
+    name: Option<String>,
+    profile_full_name: Option<String>,
+    profile_name: Option<String>,
+    e164: Option<String>,
+    /// Base64 group master key, groups v2 only.
+    master_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopMessage {
+    conversation_id: String,
+    /// `incoming` or `outgoing` for messages, something else for service notices.
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    // The one key Desktop writes in snake case
+    #[serde(rename = "sent_at")]
+    sent_at: Option<u64>,
+    body: Option<String>,
+    source_service_id: Option<String>,
+    source_uuid: Option<String>,
+    server_guid: Option<String>,
+    expire_timer: Option<u32>,
+    deleted_for_everyone: Option<bool>,
+    is_erased: Option<bool>,
+    attachments: Option<Vec<DesktopAttachment>>,
+    quote: Option<DesktopQuote>,
+    reactions: Option<Vec<DesktopReaction>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopAttachment {
+    /// Where the file is under Desktop's `attachments.noindex` directory.
+    path: Option<String>,
+    content_type: Option<String>,
+    file_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    flags: Option<u32>,
+    /// Set when Desktop encrypted the file at rest.
+    local_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopQuote {
+    id: Option<u64>,
+    author_aci: Option<String>,
+    author_uuid: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopReaction {
+    emoji: Option<String>,
+    /// Conversation id of the person who reacted.
+    from_id: Option<String>,
+    timestamp: Option<u64>,
+}
+
+/// The export as read from disk, before it is mapped to threads.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DesktopExport {
+    conversations: Vec<Value>,
+    messages: Vec<Value>,
+    /// Our own ACI, which Signal Desktop leaves out of older outgoing messages.
+    self_aci: Option<String>,
+}
+
+/// A conversation mapped to this crate's thread key and contact or group label.
+struct Conversation {
+    thread: String,
+    service_id: Option<String>,
+    label: String,
+    group: bool,
+}
+
+/// Stores the messages of a Signal Desktop export that are not in Postgres yet.
+///
+/// Messages are identified by author and sent timestamp like live ones, so history
+/// the linked device already received is skipped rather than stored twice. Files are
+/// copied into the attachment store only when Desktop's attachments directory is given.
+pub async fn import_desktop(
+    vector_db: &VectorDb,
+    format: ImportFormat,
+    path: &Path,
+    self_aci: Option<String>,
+    desktop_attachments: Option<&Path>,
+) -> anyhow::Result<ImportStats> {
+    match format {
+        ImportFormat::DesktopDb => {
+            let options = SqliteConnectOptions::new().filename(path).read_only(true);
+            let mut conn = SqliteConnection::connect_with(&options)
+                .await
+                .context("failed to open Signal Desktop database; is it decrypted?")?;
+            let (conversations, stored_aci) = read_desktop_db(&mut conn).await?;
+            let mut importer = Importer::new(
+                vector_db,
+                conversations,
+                self_aci.or(stored_aci),
+                desktop_attachments,
+            );
+
+            // Years of history do not fit in memory, so messages are read as they are stored
+            let mut messages =
+                sqlx::query_scalar::<_, String>("SELECT json FROM messages ORDER BY sent_at")
+                    .fetch(&mut conn);
+            while let Some(json) = messages.try_next().await? {
+                importer.add(serde_json::from_str(&json)).await?;
+            }
+            drop(messages);
+            conn.close().await?;
+            importer.finish().await
+        }
+        ImportFormat::Json => {
+            let json = tokio::fs::read_to_string(path).await?;
+            let export: DesktopExport =
+                serde_json::from_str(&json).context("not a Signal Desktop JSON export")?;
+            let mut importer = Importer::new(
+                vector_db,
+                export.conversations,
+                self_aci.or(export.self_aci),
+                desktop_attachments,
+            );
+            let mut messages = export.messages;
+            messages.sort_by_key(|message| message["sent_at"].as_u64());
+            for message in messages {
+                importer.add(serde_json::from_value(message)).await?;
+            }
+            importer.finish().await
+        }
+    }
+}
+
+/// Reads the conversations of a decrypted `db.sqlite`, and our own ACI if it is recorded.
+async fn read_desktop_db(
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(Vec<Value>, Option<String>)> {
+    let conversations: Vec<String> = sqlx::query_scalar("SELECT json FROM conversations")
+        .fetch_all(&mut *conn)
+        .await?;
+    // Stored as `{"id": "uuid_id", "value": "<aci>.<device id>"}`
+    let self_item: Option<String> =
+        sqlx::query_scalar("SELECT json FROM items WHERE id = 'uuid_id'")
+            .fetch_optional(&mut *conn)
+            .await?;
+
+    let self_aci = self_item
+        .and_then(|item| serde_json::from_str::<Value>(&item).ok())
+        .and_then(|item| {
+            let value = item["value"].as_str()?;
+            Some(value.split('.').next()?.to_string())
+        });
+    let conversations = conversations
+        .iter()
+        .filter_map(|json| serde_json::from_str(json).ok())
+        .collect();
+    Ok((conversations, self_aci))
+}
+
+/// Maps Desktop messages to threads and stores them in batches, in the order they are
+/// added.
+struct Importer<'a> {
+    vector_db: &'a VectorDb,
+    conversations: HashMap<String, Conversation>,
+    /// Contact label of each service id, for the senders of group messages.
+    labels: HashMap<String, String>,
+    self_aci: Option<String>,
+    desktop_attachments: Option<&'a Path>,
+    stats: ImportStats,
+    pending: Vec<ProcessedMessage>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(
+        vector_db: &'a VectorDb,
+        conversations: Vec<Value>,
+        self_aci: Option<String>,
+        desktop_attachments: Option<&'a Path>,
+    ) -> Self {
+        if self_aci.is_none() {
+            warn!("own ACI unknown; outgoing messages without a source are skipped");
+        }
+        let conversations: HashMap<String, Conversation> = conversations
+            .into_iter()
+            .filter_map(|value| match serde_json::from_value(value) {
+                Ok(conversation) => conversation_from_desktop(conversation),
+                Err(error) => {
+                    warn!(%error, "failed to parse conversation");
+                    None
+                }
+            })
+            .collect();
+        let labels = contact_labels(&conversations);
+
+        Importer {
+            vector_db,
+            stats: ImportStats {
+                conversations: conversations.len(),
+                ..Default::default()
+            },
+            conversations,
+            labels,
+            self_aci,
+            desktop_attachments,
+            pending: vec![],
+        }
+    }
+
+    async fn add(&mut self, message: serde_json::Result<DesktopMessage>) -> anyhow::Result<()> {
+        let message = match message {
+            Ok(message) => message,
+            Err(error) => {
+                warn!(%error, "failed to parse message");
+                return Ok(());
+            }
+        };
+        let mut processed = message_from_desktop(
+            &message,
+            &self.conversations,
+            &self.labels,
+            self.self_aci.as_deref(),
+        );
+        let Some(stored) = processed.first_mut() else {
+            self.stats.skipped += 1;
+            return Ok(());
+        };
+        self.stats.messages += 1;
+        if let Some(dir) = self.desktop_attachments {
+            *stored = with_desktop_attachments(self.vector_db, dir, &message, stored.clone()).await;
+        }
+
+        for processed in processed {
+            if let Some(processed) = apply_message_effects(processed, self.vector_db).await {
+                self.pending.push(processed);
+            }
+        }
+        if self.pending.len() >= BATCH_SIZE {
+            store_batch(self.vector_db, std::mem::take(&mut self.pending)).await?;
+            info!(messages = self.stats.messages, "imported messages");
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> anyhow::Result<ImportStats> {
+        if !self.pending.is_empty() {
+            store_batch(self.vector_db, self.pending).await?;
+        }
+        Ok(self.stats)
+    }
+}
+
+/// Label of each contact by service id, for the senders of group messages.
+fn contact_labels(conversations: &HashMap<String, Conversation>) -> HashMap<String, String> {
+    conversations
+        .values()
+        .filter_map(|c| Some((c.service_id.clone()?, c.label.clone())))
+        .collect()
+}
+
+fn conversation_from_desktop(conversation: DesktopConversation) -> Option<(String, Conversation)> {
+    let name = [
+        &conversation.name,
+        &conversation.profile_full_name,
+        &conversation.profile_name,
+        &conversation.e164,
+    ]
+    .into_iter()
+    .flatten()
+    .find(|name| !name.is_empty())
+    .cloned();
+
+    let mapped = match conversation.kind.as_deref() {
+        Some("group") => {
+            // Group threads are keyed by master key, which groups v1 do not have
+            let master_key = BASE64_STANDARD
+                .decode(conversation.master_key.as_deref()?)
+                .ok()?;
+            Conversation {
+                thread: hex::encode(master_key),
+                service_id: None,
+                label: name.unwrap_or_else(|| "<missing group>".to_string()),
+                group: true,
+            }
+        }
+        _ => {
+            let service_id = conversation
+                .service_id
+                .or(conversation.uuid)?
+                .to_lowercase();
+            // Same label as `format_contact` gives live messages
+            let label = match name {
+                Some(name) => format!("{name},{service_id}"),
+                None => service_id.clone(),
+            };
+            Conversation {
+                thread: service_id.clone(),
+                service_id: Some(service_id),
+                label,
+                group: false,
+            }
+        }
+    };
+    Some((conversation.id, mapped))
+}
+
+/// Maps a message and its reactions to what live messages produce, or nothing when it
+/// cannot be attributed or is not a message.
+fn message_from_desktop(
+    message: &DesktopMessage,
+    conversations: &HashMap<String, Conversation>,
+    labels: &HashMap<String, String>,
+    self_aci: Option<&str>,
+) -> Vec<ProcessedMessage> {
+    let (Some(conversation), Some(sent_at)) =
+        (conversations.get(&message.conversation_id), message.sent_at)
+    else {
+        return vec![];
+    };
+    if message.deleted_for_everyone == Some(true) || message.is_erased == Some(true) {
+        return vec![];
+    }
+    // Service ids are compared lowercased, the way they are stored
+    let self_aci = self_aci.map(str::to_lowercase);
+
+    let direction = match message.kind.as_deref() {
+        Some("incoming") => Direction::From,
+        Some("outgoing") => Direction::To,
+        _ => return vec![],
+    };
+    let source = message
+        .source_service_id
+        .clone()
+        .or(message.source_uuid.clone())
+        .map(|source| source.to_lowercase());
+    let sender = match direction {
+        Direction::From => source,
+        Direction::To => source.or(self_aci.clone()),
+    };
+    let Some(sender) = sender else {
+        return vec![];
+    };
+
+    let (contact, group) = match (conversation.group, &direction) {
+        (false, _) => (Some(conversation.label.clone()), None),
+        (true, Direction::From) => (
+            Some(
+                labels
+                    .get(&sender)
+                    .cloned()
+                    .unwrap_or_else(|| sender.clone()),
+            ),
+            Some(conversation.label.clone()),
+        ),
+        (true, Direction::To) => (None, Some(conversation.label.clone())),
+    };
+
+    let quote = message.quote.as_ref();
+    let (kind, body) = match (&message.body, quote.and_then(|quote| quote.text.as_ref())) {
+        (Some(body), Some(quoted_text)) if !body.is_empty() => (
+            MessageKind::Quote,
+            format!("Answer to message \"{quoted_text}\": {body}"),
+        ),
+        (Some(body), _) if !body.is_empty() => (MessageKind::Text, body.clone()),
+        _ => match message.attachments.as_deref() {
+            Some(attachments) if !attachments.is_empty() => (
+                MessageKind::Attachment,
+                attachments_body(attachments.iter().map(|attachment| {
+                    (
+                        attachment.file_name.as_deref(),
+                        attachment.content_type.as_deref(),
+                    )
+                })),
+            ),
+            _ => (MessageKind::Null, "Empty data message".to_string()),
+        },
+    };
+
+    let stored = ProcessedMessage {
+        kind,
+        direction: Some(direction),
+        contact: contact.clone(),
+        sender: Some(sender.clone()),
+        group: group.clone(),
+        body: Some(body),
+        attachments: None,
+        sent_at: Some(sent_at),
+        thread: Some(conversation.thread.clone()),
+        server_guid: message.server_guid.clone(),
+        target_sent_at: None,
+        target_author_aci: None,
+        emoji: None,
+        reaction_removed: false,
+        quote_author_aci: quote.and_then(|quote| {
+            quote
+                .author_aci
+                .clone()
+                .or(quote.author_uuid.clone())
+                .map(|author| author.to_lowercase())
+        }),
+        quote_sent_at: quote.and_then(|quote| quote.id),
+        expire_timer: message.expire_timer,
+        voice_note: None,
+        language: None,
+    };
+    // Desktop keeps only the current reaction of each person on the message itself
+    let reactions = message.reactions.iter().flatten().filter_map(|reaction| {
+        let reactor = conversations.get(reaction.from_id.as_ref()?)?;
+        let reactor_aci = reactor.service_id.clone()?;
+        let direction = match &self_aci {
+            Some(self_aci) if *self_aci == reactor_aci => Direction::To,
+            _ => Direction::From,
+        };
+        let emoji = reaction.emoji.clone()?;
+        Some(ProcessedMessage {
+            kind: MessageKind::Reaction,
+            direction: Some(direction),
+            contact: Some(reactor.label.clone()),
+            sender: Some(reactor_aci),
+            group: group.clone(),
+            body: Some(format!("Reacted with {emoji} to a message")),
+            attachments: None,
+            sent_at: reaction.timestamp,
+            thread: Some(conversation.thread.clone()),
+            server_guid: None,
+            target_sent_at: Some(sent_at),
+            target_author_aci: Some(sender.clone()),
+            emoji: Some(emoji),
+            reaction_removed: false,
+            quote_author_aci: None,
+            quote_sent_at: None,
+            expire_timer: None,
+            voice_note: None,
+            language: None,
+        })
+    });
+
+    let mut processed = vec![stored];
+    processed.extend(reactions);
+    processed
+}
+
+/// Copies the files of `message` from Desktop's attachments directory into the attachment
+/// store, so their text is extracted and voice notes transcribed like those of live messages.
+async fn with_desktop_attachments(
+    vector_db: &VectorDb,
+    dir: &Path,
+    message: &DesktopMessage,
+    stored: ProcessedMessage,
+) -> ProcessedMessage {
+    let mut paths = vec![];
+    let mut voice_note = None;
+    for attachment in message.attachments.iter().flatten() {
+        let Some(path) = &attachment.path else {
+            continue;
+        };
+        if attachment.local_key.is_some() {
+            warn!(path, "attachment encrypted by Signal Desktop, skipped");
+            continue;
+        }
+        let data = match tokio::fs::read(dir.join(path)).await {
+            Ok(data) => data,
+            Err(error) => {
+                warn!(path, %error, "failed to read Signal Desktop attachment");
+                continue;
+            }
+        };
+
+        // Desktop keeps the flags of the attachment pointer
+        let flags = attachment.flags.unwrap_or_default();
+        let meta = AttachmentMeta {
+            mime_type: attachment.content_type.clone(),
+            file_name: attachment.file_name.clone(),
+            width: attachment.width,
+            height: attachment.height,
+            voice_note: flags & AttachmentFlags::VoiceMessage as u32 != 0,
+            sender_aci: stored.sender.clone(),
+            sent_at: stored.sent_at,
+        };
+        match save_attachment(
+            &vector_db.pool,
+            vector_db.cipher.as_ref(),
+            &vector_db.attachments.dir,
+            &data,
+            &meta,
+        )
+        .await
+        {
+            Ok(relative) => {
+                if meta.voice_note {
+                    voice_note = Some(relative.clone());
+                }
+                paths.push(relative);
+            }
+            Err(error) => error!(path, %error, "failed to store attachment"),
+        }
+    }
+
+    let stored = ProcessedMessage {
+        attachments: (!paths.is_empty()).then_some(paths),
+        ..stored
+    };
+    match voice_note {
+        Some(file_name) => stored.with_voice_note(file_name),
+        None => stored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ALICE: &str = "0a1b2c3d-0000-4000-8000-00000000a11c";
+    const BOB: &str = "0a1b2c3d-0000-4000-8000-000000000b0b";
+    const ME: &str = "0a1b2c3d-0000-4000-8000-0000000000ee";
+    const MASTER_KEY: [u8; 32] = [7; 32];
+
+    fn conversations() -> HashMap<String, Conversation> {
+        [
+            json!({"id": "c-alice", "type": "private", "serviceId": ALICE.to_uppercase(), "name": "Alice"}),
+            json!({"id": "c-bob", "type": "private", "uuid": BOB, "profileName": "Bob"}),
+            json!({"id": "c-me", "type": "private", "serviceId": ME}),
+            json!({"id": "c-trip", "type": "group", "name": "Trip", "masterKey": BASE64_STANDARD.encode(MASTER_KEY)}),
+            json!({"id": "c-v1", "type": "group", "name": "Old group"}),
+        ]
+        .into_iter()
+        .filter_map(|value| conversation_from_desktop(serde_json::from_value(value).unwrap()))
+        .collect()
+    }
+
+    fn map(message: Value, self_aci: Option<&str>) -> Vec<ProcessedMessage> {
+        let conversations = conversations();
+        let message: DesktopMessage = serde_json::from_value(message).unwrap();
+        message_from_desktop(
+            &message,
+            &conversations,
+            &contact_labels(&conversations),
+            self_aci,
+        )
+    }
+
+    #[test]
+    fn conversations_are_keyed_like_live_threads() {
+        let conversations = conversations();
+        let alice = &conversations["c-alice"];
+        assert_eq!(alice.thread, ALICE);
+        assert_eq!(alice.label, format!("Alice,{ALICE}"));
+        assert!(!alice.group);
+        assert_eq!(conversations["c-bob"].label, format!("Bob,{BOB}"));
+        assert_eq!(conversations["c-me"].label, ME);
+
+        let trip = &conversations["c-trip"];
+        assert_eq!(trip.thread, hex::encode(MASTER_KEY));
+        assert_eq!(trip.label, "Trip");
+        assert!(trip.group && trip.service_id.is_none());
+        // Groups v1 have no master key to key them by
+        assert!(!conversations.contains_key("c-v1"));
+    }
+
+    #[test]
+    fn incoming_messages_come_from_their_source() {
+        let processed = map(
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "sent_at": 1000, "body": "hi",
+                "sourceServiceId": ALICE.to_uppercase(), "expireTimer": 60, "serverGuid": "guid",
+            }),
+            Some(ME),
+        );
+        assert_eq!(processed.len(), 1);
+        let message = &processed[0];
+        assert_eq!(message.kind, MessageKind::Text);
+        assert!(matches!(message.direction, Some(Direction::From)));
+        assert_eq!(message.sender.as_deref(), Some(ALICE));
+        assert_eq!(message.contact, Some(format!("Alice,{ALICE}")));
+        assert_eq!(message.group, None);
+        assert_eq!(message.thread.as_deref(), Some(ALICE));
+        assert_eq!(message.body.as_deref(), Some("hi"));
+        assert_eq!(message.sent_at, Some(1000));
+        assert_eq!(message.expire_timer, Some(60));
+        assert_eq!(message.server_guid.as_deref(), Some("guid"));
+    }
+
+    #[test]
+    fn outgoing_messages_without_a_source_are_ours() {
+        let message = json!({
+            "conversationId": "c-alice", "type": "outgoing", "sent_at": 1000, "body": "hi",
+        });
+        let processed = map(message.clone(), Some(&ME.to_uppercase()));
+        assert!(matches!(processed[0].direction, Some(Direction::To)));
+        assert_eq!(processed[0].sender.as_deref(), Some(ME));
+        assert_eq!(processed[0].contact, Some(format!("Alice,{ALICE}")));
+
+        // Without our own ACI they cannot be attributed
+        assert!(map(message, None).is_empty());
+    }
+
+    #[test]
+    fn group_messages_name_the_sender_and_the_group() {
+        let incoming = map(
+            json!({
+                "conversationId": "c-trip", "type": "incoming", "sent_at": 1000, "body": "hi",
+                "sourceUuid": BOB,
+            }),
+            Some(ME),
+        );
+        assert_eq!(incoming[0].contact, Some(format!("Bob,{BOB}")));
+        assert_eq!(incoming[0].group.as_deref(), Some("Trip"));
+        assert_eq!(incoming[0].thread, Some(hex::encode(MASTER_KEY)));
+
+        let outgoing = map(
+            json!({"conversationId": "c-trip", "type": "outgoing", "sent_at": 1001, "body": "yo"}),
+            Some(ME),
+        );
+        assert_eq!(outgoing[0].contact, None);
+        assert_eq!(outgoing[0].group.as_deref(), Some("Trip"));
+    }
+
+    #[test]
+    fn quotes_keep_the_quoted_text_and_message() {
+        let processed = map(
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "sent_at": 1000, "body": "sure",
+                "sourceServiceId": ALICE,
+                "quote": {"id": 900, "authorAci": BOB.to_uppercase(), "text": "dinner?"},
+            }),
+            Some(ME),
+        );
+        let message = &processed[0];
+        assert_eq!(message.kind, MessageKind::Quote);
+        assert_eq!(
+            message.body.as_deref(),
+            Some("Answer to message \"dinner?\": sure")
+        );
+        assert_eq!(message.quote_author_aci.as_deref(), Some(BOB));
+        assert_eq!(message.quote_sent_at, Some(900));
+    }
+
+    #[test]
+    fn reactions_target_the_message() {
+        let processed = map(
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "sent_at": 1000, "body": "hi",
+                "sourceServiceId": ALICE,
+                "reactions": [
+                    {"emoji": "👍", "fromId": "c-me", "timestamp": 2000},
+                    {"emoji": "❤️", "fromId": "c-bob", "timestamp": 2001},
+                    {"emoji": "😮", "fromId": "c-unknown", "timestamp": 2002},
+                ],
+            }),
+            Some(&ME.to_uppercase()),
+        );
+        assert_eq!(processed.len(), 3);
+
+        let ours = &processed[1];
+        assert_eq!(ours.kind, MessageKind::Reaction);
+        assert!(matches!(ours.direction, Some(Direction::To)));
+        assert_eq!(ours.sender.as_deref(), Some(ME));
+        assert_eq!(ours.emoji.as_deref(), Some("👍"));
+        assert_eq!(ours.sent_at, Some(2000));
+        assert_eq!(ours.target_sent_at, Some(1000));
+        assert_eq!(ours.target_author_aci.as_deref(), Some(ALICE));
+
+        let theirs = &processed[2];
+        assert!(matches!(theirs.direction, Some(Direction::From)));
+        assert_eq!(theirs.sender.as_deref(), Some(BOB));
+        assert_eq!(theirs.contact, Some(format!("Bob,{BOB}")));
+    }
+
+    #[test]
+    fn attachment_only_messages_are_kept() {
+        let processed = map(
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "sent_at": 1000,
+                "sourceServiceId": ALICE,
+                "attachments": [{"fileName": "plan.pdf", "contentType": "application/pdf"}],
+            }),
+            Some(ME),
+        );
+        assert_eq!(processed[0].kind, MessageKind::Attachment);
+        assert_eq!(processed[0].body.as_deref(), Some("Attachment: plan.pdf"));
+        // Files are only imported from Desktop's attachments directory
+        assert_eq!(processed[0].attachments, None);
+    }
+
+    #[test]
+    fn service_and_deleted_messages_are_skipped() {
+        let skipped = [
+            json!({"conversationId": "c-alice", "type": "group-v2-change", "sent_at": 1000}),
+            json!({"conversationId": "c-alice", "type": "timer-notification", "sent_at": 1000}),
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "sent_at": 1000, "body": "hi",
+                "sourceServiceId": ALICE, "deletedForEveryone": true,
+            }),
+            json!({
+                "conversationId": "c-unknown", "type": "incoming", "sent_at": 1000, "body": "hi",
+                "sourceServiceId": ALICE,
+            }),
+            json!({
+                "conversationId": "c-alice", "type": "incoming", "body": "hi",
+                "sourceServiceId": ALICE,
+            }),
+            json!({"conversationId": "c-alice", "type": "incoming", "sent_at": 1000, "body": "hi"}),
+        ];
+        for message in skipped {
+            assert!(map(message.clone(), Some(ME)).is_empty(), "{message}");
+        }
+    }
+}
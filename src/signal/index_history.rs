@@ -0,0 +1,135 @@
+use presage::{
+    manager::Registered,
+    store::{Store, Thread},
+    Manager,
+};
+use tracing::{info, warn};
+
+use crate::rag::{
+    history::{clear_checkpoints, load_checkpoint, save_checkpoint},
+    ingest::store_batch,
+    vector_db::VectorDb,
+};
+
+use super::format::{format_contact, format_thread_key};
+use super::format_message::{Direction, MessageKind};
+use super::process_incoming_message::{
+    apply_message_effects, to_processed_message, ProcessedMessage,
+};
+
+// Messages embedded per request, and between two checkpoints
+const BATCH_SIZE: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct HistoryStats {
+    pub threads: usize,
+    pub messages: usize,
+}
+
+/// Stores every message of the contact and group threads in the presage store that is not
+/// yet in Postgres.
+///
+/// Each thread resumes after the newest message stored by the previous run; `restart`
+/// reads all threads from the start again. Attachments are not downloaded.
+pub async fn index_history<S: Store>(
+    manager: &Manager<S, Registered>,
+    vector_db: &VectorDb,
+    restart: bool,
+) -> anyhow::Result<HistoryStats> {
+    if restart {
+        clear_checkpoints(&vector_db.pool).await?;
+    }
+
+    let mut threads = vec![];
+    for contact in manager.store().contacts().await?.flatten() {
+        threads.push(Thread::Contact(contact.uuid));
+    }
+    for group in manager.store().groups().await? {
+        match group {
+            Ok((key, _)) => threads.push(Thread::Group(key)),
+            Err(error) => warn!(%error, "failed to deserialize group"),
+        }
+    }
+
+    let mut stats = HistoryStats::default();
+    for thread in &threads {
+        let messages = index_thread(manager, vector_db, thread).await?;
+        stats.threads += 1;
+        stats.messages += messages;
+    }
+    Ok(stats)
+}
+
+async fn index_thread<S: Store>(
+    manager: &Manager<S, Registered>,
+    vector_db: &VectorDb,
+    thread: &Thread,
+) -> anyhow::Result<usize> {
+    let thread_key = format_thread_key(thread);
+    let from = match load_checkpoint(&vector_db.pool, &thread_key).await? {
+        Some(last_sent_at) => last_sent_at as u64 + 1,
+        None => 0,
+    };
+    let our_aci = manager.registration_data().service_ids.aci;
+
+    let mut read = 0;
+    let mut pending = vec![];
+    let mut last_sent_at = None;
+    for content in manager.store().messages(thread, from..).await? {
+        let content = match content {
+            Ok(content) => content,
+            Err(error) => {
+                warn!(%error, thread = thread_key, "failed to deserialize message");
+                continue;
+            }
+        };
+        read += 1;
+
+        let mut message = to_processed_message(manager, &content, vec![]).await;
+        // Messages sent from this device are stored with us as the sender
+        message.thread = Some(thread_key.clone());
+        if content.metadata.sender.raw_uuid() == our_aci {
+            message.direction = Some(Direction::To);
+            message.contact = match thread {
+                Thread::Contact(uuid) => Some(format_contact(uuid, manager).await),
+                Thread::Group(_) => None,
+            };
+        }
+
+        // Edits, deletes and reactions must see the messages before them stored
+        if matches!(
+            message.kind,
+            MessageKind::Edit | MessageKind::Delete | MessageKind::Reaction
+        ) {
+            flush(vector_db, &thread_key, &mut pending, last_sent_at).await?;
+        }
+        if let Some(message) = apply_message_effects(message, vector_db).await {
+            pending.push(message);
+        }
+        last_sent_at = Some(content.metadata.timestamp as i64);
+
+        if pending.len() >= BATCH_SIZE {
+            flush(vector_db, &thread_key, &mut pending, last_sent_at).await?;
+        }
+    }
+    flush(vector_db, &thread_key, &mut pending, last_sent_at).await?;
+
+    info!(thread = thread_key, read, "indexed thread history");
+    Ok(read)
+}
+
+/// Stores `pending` and moves the checkpoint of `thread` to `last_sent_at`.
+async fn flush(
+    vector_db: &VectorDb,
+    thread: &str,
+    pending: &mut Vec<ProcessedMessage>,
+    last_sent_at: Option<i64>,
+) -> anyhow::Result<()> {
+    if !pending.is_empty() {
+        store_batch(vector_db, std::mem::take(pending)).await?;
+    }
+    if let Some(last_sent_at) = last_sent_at {
+        save_checkpoint(&vector_db.pool, thread, last_sent_at).await?;
+    }
+    Ok(())
+}
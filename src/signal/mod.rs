@@ -1,22 +1,30 @@
 pub mod attachments_dir;
+pub mod bot;
 pub mod format;
 pub mod format_message;
+pub mod import_desktop;
+pub mod index_history;
 pub mod process_incoming_message;
 pub mod receive;
 pub mod send;
 pub mod upload_attachments;
 
-// fn parse_group_master_key(value: &str) -> anyhow::Result<GroupMasterKeyBytes> {
-//     let master_key_bytes = hex::decode(value)?;
-//     master_key_bytes
-//         .try_into()
-//         .map_err(|_| anyhow::format_err!("master key should be 32 bytes long"))
-// }
+use anyhow::anyhow;
+use base64::prelude::*;
+use presage::libsignal_service::prelude::ProfileKey;
+use presage::libsignal_service::zkgroup::GroupMasterKeyBytes;
 
-// fn parse_base64_profile_key(s: &str) -> anyhow::Result<ProfileKey> {
-//     let bytes = BASE64_STANDARD
-//         .decode(s)?
-//         .try_into()
-//         .map_err(|_| anyhow!("profile key of invalid length"))?;
-//     Ok(ProfileKey::create(bytes))
-// }
+pub fn parse_group_master_key(value: &str) -> anyhow::Result<GroupMasterKeyBytes> {
+    let master_key_bytes = hex::decode(value)?;
+    master_key_bytes
+        .try_into()
+        .map_err(|_| anyhow::format_err!("master key should be 32 bytes long"))
+}
+
+pub fn parse_base64_profile_key(s: &str) -> anyhow::Result<ProfileKey> {
+    let bytes = BASE64_STANDARD
+        .decode(s)?
+        .try_into()
+        .map_err(|_| anyhow!("profile key of invalid length"))?;
+    Ok(ProfileKey::create(bytes))
+}
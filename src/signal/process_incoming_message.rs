@@ -1,28 +1,71 @@
-use chrono::Local;
 use presage::{
     libsignal_service::content::{Content, ContentBody, DataMessage},
+    libsignal_service::proto::attachment_pointer::Flags as AttachmentFlags,
     manager::Registered,
-    store::Store,
+    store::{Store, Thread},
     Manager,
 };
-use sqlx::{Pool, Postgres};
 use std::path::Path;
-use tokio::fs;
+use tracing::error;
 use tracing::warn;
-use tracing::{error, info};
 
-use crate::rag::{dataframes::process_dataframe, sqlx::insert_embeddings_into_db};
+use crate::rag::{
+    attachment_store::{save_attachment, AttachmentMeta},
+    ingest::store_batch,
+    reactions::record_reaction,
+    retention::{apply_delete, record_expire_timer},
+    vector_db::VectorDb,
+};
 
-use super::format_message::{format_message, Direction, MessageEverything};
+use super::format::format_thread_key;
+use super::format_message::{
+    data_message, format_message, Direction, MessageEverything, MessageKind,
+};
 
 #[derive(Debug, Clone)]
 pub struct ProcessedMessage {
+    pub kind: MessageKind,
     pub direction: Option<Direction>,
     pub contact: Option<String>,
     pub sender: Option<String>,
     pub group: Option<String>,
     pub body: Option<String>,
     pub attachments: Option<Vec<String>>,
+    pub sent_at: Option<u64>,
+    pub thread: Option<String>,
+    pub server_guid: Option<String>,
+    /// Sent timestamp of the message an edit, delete or reaction refers to.
+    pub target_sent_at: Option<u64>,
+    /// Author of the message a reaction refers to.
+    pub target_author_aci: Option<String>,
+    pub emoji: Option<String>,
+    /// The reaction with `emoji` was taken back.
+    pub reaction_removed: bool,
+    /// Author and sent timestamp of the message this one quotes in reply.
+    pub quote_author_aci: Option<String>,
+    pub quote_sent_at: Option<u64>,
+    /// Disappearing-message timer in seconds, `Some(0)` when off.
+    pub expire_timer: Option<u32>,
+    /// File name of the voice note among `attachments`.
+    pub voice_note: Option<String>,
+    /// Language detected when `body` was transcribed from `voice_note`.
+    pub language: Option<String>,
+}
+
+/// Body of a voice note until it is transcribed.
+pub const VOICE_NOTE_BODY: &str = "Voice note";
+
+impl ProcessedMessage {
+    /// Marks `file_name` as the voice note of the message. Voice notes come without text,
+    /// so the message is kept as text with a placeholder body until it is transcribed.
+    pub fn with_voice_note(mut self, file_name: String) -> Self {
+        if matches!(self.kind, MessageKind::Null | MessageKind::Attachment) {
+            self.kind = MessageKind::Text;
+            self.body = Some(VOICE_NOTE_BODY.to_string());
+        }
+        self.voice_note = Some(file_name);
+        self
+    }
 }
 
 // Note to developers, this is a good example of a function you can use as a source of inspiration
@@ -31,16 +74,11 @@ pub async fn process_incoming_message<S: Store>(
     manager: &mut Manager<S, Registered>,
     attachments_dir: &Path,
     content: &Content,
-    pg_pool: &Pool<Postgres>,
+    vector_db: &VectorDb,
 ) -> ProcessedMessage {
-    let MessageEverything {
-        direction,
-        contact,
-        group,
-        body,
-    } = format_message(manager, content).await;
     // println!("{}\n{}\n",msg_prefix,msg_content);
     let mut path_vec = vec![];
+    let mut voice_note = None;
 
     let sender = content.metadata.sender.raw_uuid();
     if let ContentBody::DataMessage(DataMessage { attachments, .. }) = &content.body {
@@ -50,81 +88,149 @@ pub async fn process_incoming_message<S: Store>(
                 continue;
             };
 
-            let extensions = mime_guess::get_mime_extensions_str(
-                attachment_pointer
-                    .content_type
-                    .as_deref()
-                    .unwrap_or("application/octet-stream"),
-            );
-            let extension = extensions.and_then(|e| e.first()).unwrap_or(&"bin");
-            let timestamp = Local::now().format("%Y-%m-%d-%H-%M-%s").to_string();
-            let default_name = format!("{}.{}", &timestamp, extension);
-            let mut filename: String = attachment_pointer
-                .file_name
-                .clone()
-                .unwrap_or_else(|| default_name.clone());
-
-            if filename != default_name {
-                filename = format!("{}-{}", timestamp, filename);
-            }
-
-            path_vec.push(filename.clone());
-            let file_path = attachments_dir.join(filename);
-            match fs::write(&file_path, &attachment_data).await {
-                Ok(_) => info!(%sender, file_path =% file_path.display(), "saved attachment"),
-                Err(error) => error!(
-                    %sender,
-                    file_path =% file_path.display(),
-                    %error,
-                    "failed to write attachment"
-                ),
+            let flags = attachment_pointer.flags.unwrap_or_default();
+            let meta = AttachmentMeta {
+                mime_type: attachment_pointer.content_type.clone(),
+                file_name: attachment_pointer.file_name.clone(),
+                width: attachment_pointer.width,
+                height: attachment_pointer.height,
+                voice_note: flags & AttachmentFlags::VoiceMessage as u32 != 0,
+                sender_aci: Some(sender.to_string()),
+                sent_at: Some(content.metadata.timestamp),
+            };
+            let path = match save_attachment(
+                &vector_db.pool,
+                vector_db.cipher.as_ref(),
+                attachments_dir,
+                &attachment_data,
+                &meta,
+            )
+            .await
+            {
+                Ok(path) => path,
+                Err(error) => {
+                    error!(%sender, %error, "failed to store attachment");
+                    continue;
+                }
+            };
+            if meta.voice_note {
+                voice_note = Some(path.clone());
             }
+            path_vec.push(path);
         }
     }
 
-    let processed_message = ProcessedMessage {
-        attachments: if path_vec.len() > 0 {
-            Some(path_vec)
+    let mut processed_message = to_processed_message(manager, content, path_vec).await;
+    if let Some(file_name) = voice_note {
+        processed_message = processed_message.with_voice_note(file_name);
+    }
+
+    store_in_db(processed_message.clone(), vector_db).await;
+
+    processed_message
+}
+
+/// Builds the [`ProcessedMessage`] for `content`, with `attachments` as the names of files
+/// already saved for it.
+pub async fn to_processed_message<S: Store>(
+    manager: &Manager<S, Registered>,
+    content: &Content,
+    attachments: Vec<String>,
+) -> ProcessedMessage {
+    let MessageEverything {
+        kind,
+        direction,
+        contact,
+        group,
+        body,
+        target_sent_at,
+        expire_timer,
+    } = format_message(manager, content).await;
+    let reaction = data_message(content).and_then(|message| message.reaction.as_ref());
+    let quote = data_message(content).and_then(|message| message.quote.as_ref());
+    ProcessedMessage {
+        kind,
+        attachments: if attachments.len() > 0 {
+            Some(attachments)
         } else {
             None
         },
         direction,
         contact,
-        sender: Some(sender.to_string()),
+        sender: Some(content.metadata.sender.raw_uuid().to_string()),
         group,
         body,
-    };
+        sent_at: Some(content.metadata.timestamp),
+        thread: Thread::try_from(content)
+            .ok()
+            .map(|thread| format_thread_key(&thread)),
+        server_guid: content.metadata.server_guid.map(|guid| guid.to_string()),
+        target_sent_at,
+        target_author_aci: reaction.and_then(|reaction| reaction.target_author_aci.clone()),
+        emoji: reaction.and_then(|reaction| reaction.emoji.clone()),
+        reaction_removed: reaction
+            .and_then(|reaction| reaction.remove)
+            .unwrap_or(false),
+        quote_author_aci: quote.and_then(|quote| quote.author_aci.clone()),
+        quote_sent_at: quote.and_then(|quote| quote.id),
+        expire_timer,
+        voice_note: None,
+        language: None,
+    }
+}
 
-    store_in_db(processed_message.clone(), pg_pool).await;
+pub async fn store_in_db(processed_message: ProcessedMessage, vector_db: &VectorDb) {
+    let Some(processed_message) = apply_message_effects(processed_message, vector_db).await else {
+        return;
+    };
 
-    processed_message
+    match &vector_db.ingest {
+        Some(ingest) => ingest.enqueue(processed_message).await,
+        None => {
+            if let Err(error) = store_batch(vector_db, vec![processed_message]).await {
+                error!(%error, "failed to store message");
+            }
+        }
+    }
 }
 
-pub async fn store_in_db(processed_message: ProcessedMessage, pg_pool: &Pool<Postgres>) {
-    let msg = processed_message.body.clone().unwrap_or(String::new());
-
-    match msg.as_str() {
-        "failed to derive thread from content" => (),
-        "Null message (for example deleted)" => (),
-        "is calling!" => (),
-        "is typing..." => (),
-        "got PNI signature message" => (),
-        "Empty data message" => (),
-        "presage" => (),
-        "failed to display desktop notification" => (),
-        "Something went wrong!" => (),
-        s if s.starts_with("got Delivery receipt") => (),
-        s if s.starts_with("got Read receipt") => (),
-        s if s.starts_with("new story:") => (),
-        s if s.starts_with("receipt for messages sent at") => (),
-        s if s.starts_with("Reacted with ") => (),
-        _ => {
-            // println!("{:#?}", msg);
-
-            let messages_with_embedding = process_dataframe(&vec![processed_message.clone()]).await;
-
-            _ = insert_embeddings_into_db(pg_pool, messages_with_embedding).await;
+/// Applies what `processed_message` does to stored messages (timers, deletes and reactions)
+/// and returns it when it still has to be stored. Edits are returned too: storing applies
+/// them after the messages queued before them, so the receive loop never embeds.
+pub async fn apply_message_effects(
+    processed_message: ProcessedMessage,
+    vector_db: &VectorDb,
+) -> Option<ProcessedMessage> {
+    if let (Some(thread), Some(timer), Some(sent_at)) = (
+        &processed_message.thread,
+        processed_message.expire_timer,
+        processed_message.sent_at,
+    ) {
+        if let Err(error) =
+            record_expire_timer(&vector_db.pool, thread, timer, sent_at as i64).await
+        {
+            error!(%error, "failed to record disappearing-message timer");
+        }
+    }
+
+    // Deletes act on what is stored whatever the policy says about storing them
+    if processed_message.kind == MessageKind::Delete {
+        if let Err(error) = apply_delete(vector_db, &processed_message).await {
+            error!(%error, "failed to apply delete");
         }
+        return None;
     }
-    ()
+
+    // Reactions are metadata of their target; the policy only decides about the text
+    if processed_message.kind == MessageKind::Reaction {
+        if let Err(error) = record_reaction(&vector_db.pool, &processed_message).await {
+            error!(%error, "failed to record reaction");
+        }
+    }
+
+    if !vector_db.policy.persists(processed_message.kind) {
+        return None;
+    }
+
+    Some(processed_message)
 }
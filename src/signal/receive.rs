@@ -1,22 +1,23 @@
-use std::path::Path;
-
 use anyhow::Context as _;
 use futures::pin_mut;
 use futures::StreamExt;
 use presage::model::messages::Received;
 use presage::{manager::Registered, store::Store, Manager};
-use sqlx::Pool;
-use sqlx::Postgres;
+use tokio::sync::mpsc;
+use tracing::warn;
 
+use crate::rag::vector_db::VectorDb;
 use crate::signal::attachments_dir::attachments_dir;
+use crate::signal::bot::{answer_question, question_for_bot, send_reply, BotConfig};
 use crate::signal::process_incoming_message::process_incoming_message;
 
 pub async fn receive<S: Store>(
     manager: &mut Manager<S, Registered>,
-    pg_pool: &Pool<Postgres>,
+    vector_db: &VectorDb,
+    bot: &BotConfig,
 ) -> anyhow::Result<()> {
     println!("Start contact");
-    let attachments_dir = attachments_dir().await?;
+    let attachments_dir = attachments_dir(&vector_db.attachments.dir).await?;
     println!("{:?}", attachments_dir);
     let messages = manager
         .receive_messages()
@@ -24,20 +25,39 @@ pub async fn receive<S: Store>(
         .context("failed to initialize messages stream")?;
 
     pin_mut!(messages);
+    // Answers are generated on their own tasks; only sending them needs the manager
+    let (reply_tx, mut replies) = mpsc::channel(16);
 
-    while let Some(content) = messages.next().await {
-        // println!("{:?}",content);
-        match content {
-            Received::QueueEmpty => println!("done with synchronization"),
-            Received::Contacts => println!("got contacts synchronization"),
-            Received::Content(content) => {
-                _ = process_incoming_message(
-                    manager,
-                    Path::new(&attachments_dir),
-                    &content,
-                    &pg_pool,
-                )
-                .await;
+    loop {
+        tokio::select! {
+            content = messages.next() => {
+                let Some(content) = content else {
+                    break;
+                };
+                // println!("{:?}",content);
+                match content {
+                    Received::QueueEmpty => println!("done with synchronization"),
+                    Received::Contacts => println!("got contacts synchronization"),
+                    Received::Content(content) => {
+                        _ = process_incoming_message(manager, &attachments_dir, &content, vector_db)
+                            .await;
+                        if let Some(question) = question_for_bot(manager, &content, bot) {
+                            let (vector_db, bot, reply_tx) =
+                                (vector_db.clone(), bot.clone(), reply_tx.clone());
+                            tokio::spawn(async move {
+                                match answer_question(&vector_db, question, &bot).await {
+                                    Ok(reply) => _ = reply_tx.send(reply).await,
+                                    Err(error) => warn!(%error, "bot failed to answer"),
+                                }
+                            });
+                        }
+                    }
+                }
+            }
+            Some(reply) = replies.recv() => {
+                if let Err(error) = send_reply(manager, reply).await {
+                    warn!(%error, "bot failed to send its answer");
+                }
             }
         }
     }
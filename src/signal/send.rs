@@ -1,4 +1,3 @@
-use std::path::Path;
 use std::time::Duration;
 use std::time::UNIX_EPOCH;
 
@@ -10,10 +9,9 @@ use presage::model::messages::Received;
 use presage::{
     libsignal_service::content::ContentBody, manager::Registered, store::Store, Manager,
 };
-use sqlx::Pool;
-use sqlx::Postgres;
 use tracing::info;
 
+use crate::rag::vector_db::VectorDb;
 use crate::types::Recipient;
 use crate::signal::attachments_dir::attachments_dir;
 use crate::signal::process_incoming_message::process_incoming_message;
@@ -22,9 +20,9 @@ pub async fn send<S: Store>(
     manager: &mut Manager<S, Registered>,
     recipient: Recipient,
     msg: impl Into<ContentBody>,
-    pg_pool: &Pool<Postgres>,
+    vector_db: &VectorDb,
 ) -> anyhow::Result<()> {
-    let attachments_dir = attachments_dir().await?;
+    let attachments_dir = attachments_dir(&vector_db.attachments.dir).await?;
 
     let timestamp = std::time::SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -51,9 +49,9 @@ pub async fn send<S: Store>(
             Received::Content(content) => {
                 _ = process_incoming_message(
                     manager,
-                    Path::new(&attachments_dir),
+                    &attachments_dir,
                     &content,
-                    pg_pool
+                    vector_db
                 )
                 .await;
             }
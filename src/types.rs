@@ -1,3 +1,5 @@
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use presage::libsignal_service::configuration::SignalServers;
 use presage::libsignal_service::prelude::phonenumber::PhoneNumber;
 use presage::libsignal_service::prelude::ProfileKey;
@@ -6,14 +8,70 @@ use presage::libsignal_service::zkgroup::GroupMasterKeyBytes;
 use std::path::PathBuf;
 use url::Url;
 
+use crate::rag::attachments::AttachmentConfig;
+use crate::rag::dataframes::ChunkConfig;
+use crate::rag::embedder::EmbedderConfig;
+use crate::rag::encryption::EncryptionConfig;
+use crate::rag::export::{ExportFilter, ExportFormat};
+use crate::rag::generate::DEFAULT_OLLAMA_URL;
+use crate::rag::index::{IndexKind, IndexParams, QueryTuning};
+use crate::rag::ingest::{IngestConfig, StoragePolicy};
+use crate::rag::retention::RetentionConfig;
+use crate::rag::search::{parse_datetime, Granularity};
+use crate::rag::threads::parse_message_id;
+use crate::rag::windows::WindowConfig;
+use crate::signal::bot::BotConfig;
+use crate::signal::import_desktop::ImportFormat;
+use crate::signal::{parse_base64_profile_key, parse_group_master_key};
+
 pub enum Recipient {
     Contact(Uuid),
     Group(GroupMasterKeyBytes),
 }
 
+#[derive(Parser)]
+#[clap(about = "Sync Signal messages into a Postgres vector database")]
 pub struct Args {
+    #[clap(long = "db-path", short = 'd', help = "path to the presage sled store")]
     pub db_path: Option<PathBuf>,
+
+    #[clap(
+        help = "passphrase to encrypt the local storage",
+        long = "passphrase",
+        short = 'p'
+    )]
     pub passphrase: Option<String>,
+
+    #[clap(
+        long = "ollama-url",
+        env = "OLLAMA_URL",
+        default_value = DEFAULT_OLLAMA_URL,
+        help = "Base URL of the Ollama server that answers questions and captions images"
+    )]
+    pub ollama_url: String,
+
+    #[clap(flatten)]
+    pub embedder: EmbedderConfig,
+
+    #[clap(flatten)]
+    pub ingest: IngestConfig,
+
+    #[clap(flatten)]
+    pub chunking: ChunkConfig,
+
+    #[clap(flatten)]
+    pub policy: StoragePolicy,
+
+    #[clap(flatten)]
+    pub retention: RetentionConfig,
+
+    #[clap(flatten)]
+    pub attachments: AttachmentConfig,
+
+    #[clap(flatten)]
+    pub encryption: EncryptionConfig,
+
+    #[clap(subcommand)]
     pub subcommand: Cmd,
 }
 impl Args {
@@ -21,7 +79,17 @@ impl Args {
         Args {
             db_path: None,
             passphrase: None,
-            subcommand: Cmd::Receive,
+            ollama_url: String::from(DEFAULT_OLLAMA_URL),
+            embedder: EmbedderConfig::default(),
+            ingest: IngestConfig::default(),
+            chunking: ChunkConfig::default(),
+            policy: StoragePolicy::default(),
+            retention: RetentionConfig::default(),
+            attachments: AttachmentConfig::default(),
+            encryption: EncryptionConfig::default(),
+            subcommand: Cmd::Receive {
+                bot: BotConfig::default(),
+            },
             // subcommand: Cmd::Send { uuid: uuid::uuid!(""), message: String::from("todo!()"), attachment_filepath: vec![] },
             // subcommand: Cmd::Whoami,
             // subcommand: Cmd::SyncContacts,
@@ -29,61 +97,361 @@ impl Args {
     }
 }
 
+impl Cmd {
+    /// Whether the command embeds text, so the embedding server must be reachable and
+    /// serve the active model. The others never contact it.
+    pub fn embeds(&self) -> bool {
+        match self {
+            // Sending first stores what is waiting on the Signal queue
+            Cmd::Receive { .. }
+            | Cmd::Send { .. }
+            | Cmd::SendToGroup { .. }
+            | Cmd::Search { .. }
+            | Cmd::BuildWindows { .. }
+            | Cmd::Ask { .. }
+            | Cmd::Import { .. }
+            | Cmd::ImportDesktop { .. }
+            | Cmd::IndexHistory { .. } => true,
+            Cmd::Export { query, .. } => query.is_some(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
 pub enum Cmd {
+    #[clap(about = "Register using a phone number")]
     Register {
+        #[clap(long = "servers", short = 's', default_value = "staging")]
         servers: SignalServers,
+        #[clap(long, help = "Phone Number to register with in E.164 format")]
         phone_number: PhoneNumber,
+        #[clap(long)]
         use_voice_call: bool,
+        #[clap(
+            long = "captcha",
+            help = "Captcha obtained from https://signalcaptchas.org/registration/generate.html"
+        )]
         captcha: Url,
+        #[clap(long, help = "Force to register again if already registered")]
         force: bool,
     },
+    #[clap(
+        about = "Generate a QR code to scan with Signal for iOS or Android to link this client as secondary device"
+    )]
     LinkDevice {
         /// Possible values: staging, production
+        #[clap(long, short = 's', default_value = "production")]
         servers: SignalServers,
+        #[clap(
+            long,
+            short = 'n',
+            help = "Name of the device to register in the primary client"
+        )]
         device_name: String,
     },
+    #[clap(about = "Add a new linked device")]
     AddDevice {
+        #[clap(long = "url", help = "the url provided by the device to be linked")]
         url: Url,
     },
+    #[clap(about = "Unlink a linked device")]
     UnlinkDevice {
+        #[clap(long)]
         device_id: i64,
     },
+    #[clap(about = "Show linked devices")]
     ListDevices,
+    #[clap(about = "Get information on the registered user")]
     Whoami,
+    #[clap(about = "Retrieve the user profile")]
     RetrieveProfile {
         /// Id of the user to retrieve the profile. When omitted, retrieves the registered user
         /// profile.
+        #[clap(long)]
         uuid: Uuid,
         /// Base64-encoded profile key of user to be able to access their profile
+        #[clap(long, value_parser = parse_base64_profile_key)]
         profile_key: Option<ProfileKey>,
     },
-    Receive,
+    #[clap(about = "Receive all pending messages and store them in the vector database")]
+    Receive {
+        #[clap(flatten)]
+        bot: BotConfig,
+    },
+    #[clap(about = "List groups")]
     ListGroups,
+    #[clap(about = "List contacts")]
     ListContacts,
+    #[clap(about = "List messages")]
     ListMessages {
+        #[clap(
+            long,
+            short = 'u',
+            help = "uuid of the contact",
+            required_unless_present = "group_master_key"
+        )]
         recipient_uuid: Option<Uuid>,
+        #[clap(
+            long,
+            short = 'k',
+            help = "Master Key of the V2 group (hex string)",
+            value_parser = parse_group_master_key,
+            required_unless_present = "recipient_uuid"
+        )]
         group_master_key: Option<GroupMasterKeyBytes>,
+        #[clap(long, help = "start from the following date (UNIX timestamp)")]
         from: Option<u64>,
     },
+    #[clap(about = "List installed sticker packs")]
     ListStickerPacks,
-    GetContact {
-        uuid: Uuid,
-    },
+    #[clap(about = "Get a single contact by UUID")]
+    GetContact { uuid: Uuid },
+    #[clap(about = "Find a contact in the embedded DB")]
     FindContact {
+        #[clap(long, short = 'u', help = "contact UUID")]
         uuid: Option<Uuid>,
+        #[clap(long, short = 'p', help = "contact phone number")]
         phone_number: Option<PhoneNumber>,
+        #[clap(long, short = 'n', help = "contact name")]
         name: Option<String>,
     },
+    #[clap(about = "Send a message")]
     Send {
+        #[clap(long, short = 'u', help = "uuid of the recipient")]
         uuid: Uuid,
+        #[clap(long, short = 'm', help = "Contents of the message to send")]
         message: String,
+        #[clap(long = "attach", help = "Path to a file to attach, can be repeated")]
         attachment_filepath: Vec<PathBuf>,
     },
+    #[clap(about = "Send a message to group")]
     SendToGroup {
+        #[clap(long, short = 'm', help = "Contents of the message to send")]
         message: String,
+        #[clap(
+            long,
+            short = 'k',
+            help = "Master Key of the V2 group (hex string)",
+            value_parser = parse_group_master_key
+        )]
         master_key: GroupMasterKeyBytes,
+        #[clap(long = "attach", help = "Path to a file to attach, can be repeated")]
         attachment_filepath: Vec<PathBuf>,
     },
+    #[clap(about = "Request contacts from the primary device and wait for them")]
     SyncContacts,
+    #[clap(about = "Print various statistics useful for debugging")]
     Stats,
+    #[clap(about = "Semantic search over the stored messages")]
+    Search {
+        #[clap(help = "Text to search for")]
+        query: String,
+        #[clap(
+            long,
+            short = 'l',
+            default_value_t = 10,
+            help = "Maximum number of results"
+        )]
+        limit: i64,
+        #[clap(
+            long,
+            short = 'c',
+            help = "Only messages whose contact contains this text"
+        )]
+        contact: Option<String>,
+        #[clap(
+            long,
+            short = 'g',
+            help = "Only messages whose group name contains this text"
+        )]
+        group: Option<String>,
+        #[clap(
+            long,
+            value_parser = ["to", "from"],
+            help = "Only sent (to) or received (from) messages"
+        )]
+        direction: Option<String>,
+        #[clap(
+            long,
+            value_parser = parse_datetime,
+            help = "Only messages from this date (YYYY-MM-DD or RFC 3339)"
+        )]
+        since: Option<DateTime<Utc>>,
+        #[clap(
+            long,
+            value_parser = parse_datetime,
+            help = "Only messages before this date (YYYY-MM-DD or RFC 3339)"
+        )]
+        until: Option<DateTime<Utc>>,
+        #[clap(
+            long,
+            short = 't',
+            help = "Only this conversation: contact uuid or group master key (hex string)"
+        )]
+        thread: Option<String>,
+        #[clap(
+            long,
+            help = "One result per message, showing long messages in full instead of the matching chunk"
+        )]
+        per_message: bool,
+        #[clap(long, help = "Also search the earlier text of edited messages")]
+        include_revisions: bool,
+        #[clap(long, help = "Only messages that got this reaction, e.g. ❤️")]
+        reaction: Option<String>,
+        #[clap(long, help = "Only messages you reacted to")]
+        reacted_by_me: bool,
+        #[clap(
+            long,
+            default_value_t = 0.0,
+            help = "Rank messages with more reactions higher; 0.1 is a gentle nudge"
+        )]
+        reaction_weight: f64,
+        #[clap(
+            long,
+            value_enum,
+            default_value = "message",
+            help = "Search single messages or conversation windows"
+        )]
+        granularity: Granularity,
+        #[clap(flatten)]
+        tuning: QueryTuning,
+    },
+    #[clap(about = "Build or rebuild the approximate nearest-neighbour index")]
+    Index {
+        #[clap(value_enum, required_unless_present = "rebuild")]
+        kind: Option<IndexKind>,
+        #[clap(
+            long,
+            conflicts_with = "kind",
+            help = "Rebuild the existing index with its current settings"
+        )]
+        rebuild: bool,
+        #[clap(flatten)]
+        params: IndexParams,
+    },
+    #[clap(about = "Apply pending database migrations and show the schema version")]
+    Migrate,
+    #[clap(about = "Group stored messages into conversation windows and embed them")]
+    BuildWindows {
+        #[clap(
+            long,
+            short = 't',
+            help = "Only this conversation: contact uuid or group master key (hex string)"
+        )]
+        thread: Option<String>,
+        #[clap(flatten)]
+        config: WindowConfig,
+    },
+    #[clap(about = "Answer questions from the stored messages with a local model")]
+    Ask {
+        #[clap(help = "Question to answer", required_unless_present = "batch")]
+        question: Option<String>,
+        #[clap(
+            long,
+            conflicts_with = "question",
+            help = "Answer the canned list of questions instead"
+        )]
+        batch: bool,
+        #[clap(
+            long,
+            short = 'm',
+            default_value = "llama3.2",
+            help = "Ollama model used to answer"
+        )]
+        model: String,
+        #[clap(
+            long,
+            short = 'l',
+            default_value_t = 10,
+            help = "Number of messages to retrieve"
+        )]
+        limit: i64,
+        #[clap(
+            long,
+            default_value_t = 2048,
+            help = "Token budget for the retrieved messages"
+        )]
+        max_context_tokens: usize,
+        #[clap(
+            long,
+            short = 'c',
+            help = "Only use messages whose contact contains this text"
+        )]
+        contact: Option<String>,
+        #[clap(
+            long,
+            short = 'g',
+            help = "Only use messages whose group name contains this text"
+        )]
+        group: Option<String>,
+        #[clap(long, help = "Do not add the messages that matching replies quote")]
+        no_parents: bool,
+    },
+    #[clap(about = "Print the reply chain around a stored message")]
+    Thread {
+        #[clap(
+            value_parser = parse_message_id,
+            help = "Row id of the message, as shown by search and ask; not a w# window"
+        )]
+        id: i64,
+    },
+    #[clap(about = "Re-embed every stored vector with another model, then switch to it")]
+    Reembed {
+        #[clap(help = "Embedding model to switch to, served by the configured backend")]
+        model: String,
+        #[clap(long, default_value_t = 32, help = "Rows embedded per request")]
+        batch_size: usize,
+    },
+    #[clap(about = "Seal the texts and attachments stored before encryption at rest was set up")]
+    EncryptExisting,
+    #[clap(about = "Write stored messages to a file for analysis elsewhere")]
+    Export {
+        #[clap(help = "File to write")]
+        output: PathBuf,
+        #[clap(long, value_enum, default_value = "jsonl")]
+        format: ExportFormat,
+        #[clap(long, help = "Include the embedding vector of every row")]
+        embeddings: bool,
+        #[clap(long, help = "Only the results of this search, best first")]
+        query: Option<String>,
+        #[clap(
+            long,
+            short = 'l',
+            default_value_t = 100,
+            help = "Maximum number of search results, with --query"
+        )]
+        limit: i64,
+        #[clap(flatten)]
+        filter: ExportFilter,
+    },
+    #[clap(
+        about = "Store the messages of a jsonl or parquet export",
+        long_about = "Store the messages of a jsonl or parquet export. Exports hold the current text of messages only: earlier revisions, reactions and the records of attachment files are not imported."
+    )]
+    Import {
+        #[clap(help = "File written by export")]
+        path: PathBuf,
+        #[clap(long, value_enum, default_value = "jsonl")]
+        format: ExportFormat,
+    },
+    #[clap(about = "Store the history of a Signal Desktop export")]
+    ImportDesktop {
+        #[clap(help = "Decrypted db.sqlite, or JSON export with --format json")]
+        path: PathBuf,
+        #[clap(long, value_enum, default_value = "desktop-db")]
+        format: ImportFormat,
+        #[clap(long, help = "Own ACI, when the export does not record it")]
+        self_aci: Option<String>,
+        #[clap(
+            long,
+            help = "Signal Desktop's attachments.noindex directory, to import files too; without it only message bodies are imported"
+        )]
+        desktop_attachments: Option<PathBuf>,
+    },
+    #[clap(about = "Store the message history already in the Signal store")]
+    IndexHistory {
+        #[clap(long, help = "Read every thread from the start instead of resuming")]
+        restart: bool,
+    },
 }